@@ -4,10 +4,12 @@ extern crate kvs;
 extern crate log;
 extern crate stderrlog;
 
-use kvs::{KvStore, KvsEngine, Options, Result};
-use std::env::current_dir;
-use std::net::SocketAddr;
+use kvs::{KvsClient, KvsError, Options, Result, ScanOptions, WriteBatch};
+use std::io::{self, BufRead};
+use std::ops::Bound;
 use std::process::exit;
+use std::time::Duration;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -15,7 +17,7 @@ use structopt::StructOpt;
     name = "kvs-client",
     about = "The internal client implementation of KvStore, accessed via the command line"
 )]
-struct KvsClient {
+struct ClientCli {
     #[structopt(subcommand)]
     command: Command,
     #[structopt(flatten)]
@@ -24,49 +26,171 @@ struct KvsClient {
 
 #[derive(StructOpt, Debug)]
 enum Command {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+        #[structopt(long, help = "Expires the key after this many seconds")]
+        ttl: Option<u64>,
+    },
+    /// Expires an existing key after the given number of seconds
+    Expire {
+        key: String,
+        seconds: u64,
+    },
+    /// Prints the seconds left before a key expires
+    Ttl {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Adds to the integer stored at a key and prints the result
+    #[structopt(setting = AppSettings::AllowNegativeNumbers)]
+    Incr {
+        key: String,
+        #[structopt(
+            default_value = "1",
+            help = "The amount to add, which may be negative"
+        )]
+        delta: i64,
+    },
+    /// Applies writes read from stdin atomically, one per line:
+    /// `set <key> <value>` or `rm <key>`
+    Batch,
+    /// Lists key/value pairs in key order, one tab-separated pair per line
+    Scan {
+        #[structopt(long, help = "Starts the scan at this key (inclusive)")]
+        start: Option<String>,
+        #[structopt(long, help = "Ends the scan before this key (exclusive)")]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Only lists keys starting with this prefix",
+            conflicts_with_all = &["start", "end"]
+        )]
+        prefix: Option<String>,
+        #[structopt(long, help = "Lists at most this many pairs")]
+        limit: Option<usize>,
+        #[structopt(long, help = "Lists pairs in descending key order")]
+        reverse: bool,
+    },
+    /// Lists keys in order, one per line
+    Keys {
+        #[structopt(
+            long,
+            help = "Only lists keys starting with this prefix",
+            default_value = ""
+        )]
+        prefix: String,
+    },
+    /// Prints the number of keys in the store
+    Count,
 }
 
-fn main() -> Result<()> {
+fn main() {
     stderrlog::new().init().unwrap();
-    let config = KvsClient::from_args();
+    let config = ClientCli::from_args();
     info!("KvsClient version: {}", env!("CARGO_PKG_VERSION"));
-    info!("Listening on port: {}", config.options.socket);
-    info!("Running on engine: {}", config.options.engine);
+    info!("Connecting to: {}", config.options.socket);
 
-    let mut store = KvStore::open(current_dir()?)?;
-    let mut exit_code = 0;
+    if let Err(error) = run(config) {
+        eprintln!(kvs_error!(), error);
+        exit(1);
+    }
+}
+
+fn run(config: ClientCli) -> Result<()> {
+    let mut client = KvsClient::connect(config.options.socket)?;
 
     match config.command {
-        Command::Get { key, .. } => match store.get(key) {
-            Ok(optional_string) => {
-                if let Some(found_string) = optional_string {
-                    println!(successful_get_with_result!(), found_string);
-                } else {
-                    println!(successful_get_without_result!());
-                }
+        Command::Get { key } => {
+            if let Some(found_string) = client.get(key)? {
+                println!(successful_get_with_result!(), found_string);
+            } else {
+                println!(successful_get_without_result!());
             }
-            Err(error) => {
-                eprintln!(kvs_error!(), error);
-                exit_code = 1;
+        }
+        Command::Set { key, value, ttl } => match ttl {
+            Some(ttl) => {
+                client.set_with_ttl(key, value, Duration::from_secs(ttl))?
             }
+            None => client.set(key, value)?,
         },
-        Command::Set { key, value, .. } => match store.set(key, value) {
-            Ok(()) => {}
-            Err(error) => {
-                eprintln!(kvs_error!(), error);
-                exit_code = 1;
-            }
+        Command::Expire { key, seconds } => {
+            client.expire(key, Duration::from_secs(seconds))?
+        }
+        Command::Ttl { key } => match client.ttl(key)? {
+            // Round up, so a key that is about to expire does not show 0.
+            Some(ttl) => println!("{}", ttl.as_millis().div_ceil(1000)),
+            None => println!(successful_ttl_without_expiry!()),
         },
-        Command::Rm { key, .. } => match store.remove(key) {
-            Ok(()) => {}
-            Err(error) => {
-                println!(kvs_error!(), error);
-                exit_code = 1;
+        Command::Rm { key } => client.remove(key)?,
+        Command::Incr { key, delta } => {
+            println!("{}", client.incr(key, delta)?)
+        }
+        Command::Batch => {
+            client.write_batch(read_batch(io::stdin().lock())?)?
+        }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            reverse,
+        } => {
+            let mut options = ScanOptions::new();
+            options.limit = limit;
+            options.reverse = reverse;
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix(&prefix, options)?,
+                None => client.scan(
+                    (
+                        start.map_or(Bound::Unbounded, Bound::Included),
+                        end.map_or(Bound::Unbounded, Bound::Excluded),
+                    ),
+                    options,
+                )?,
+            };
+            for (key, value) in pairs {
+                println!(successful_scan_pair!(), key, value);
             }
-        },
+        }
+        Command::Keys { prefix } => {
+            for key in client.keys(&prefix)? {
+                println!("{}", key);
+            }
+        }
+        Command::Count => println!("{}", client.len()?),
     };
-    exit(exit_code)
+    Ok(())
+}
+
+/// Parses a batch with one write per line. Blank lines are skipped, and
+/// everything after the key of a `set` is taken as its value.
+fn read_batch(input: impl BufRead) -> Result<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    for (line_number, line) in input.lines().enumerate() {
+        let line = line?;
+        let mut parts = line.trim().splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(""), None, None) => {}
+            (Some("set"), Some(key), Some(value)) => {
+                batch.set(key.to_owned(), value.to_owned());
+            }
+            (Some("rm"), Some(key), None) => {
+                batch.remove(key.to_owned());
+            }
+            _ => {
+                return Err(KvsError::InvalidInput(format!(
+                    "Invalid batch operation on line {}: {}",
+                    line_number + 1,
+                    line
+                )))
+            }
+        }
+    }
+    Ok(batch)
 }
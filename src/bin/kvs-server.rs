@@ -1,12 +1,13 @@
 #[macro_use]
-extern crate kvs;
-#[macro_use]
 extern crate log;
 extern crate stderrlog;
 
-use kvs::{KvStore, KvsEngine, Options, Result};
+use kvs::{
+    Durability, Engine, KvStoreOptions, KvsEngine, KvsServer, NaiveThreadPool,
+    Options, RayonThreadPool, Result, ServerOptions, SharedQueueThreadPool,
+    SledKvsEngine, ThreadPool, ThreadPoolType,
+};
 use std::env::current_dir;
-use std::net::SocketAddr;
 use std::process::exit;
 use structopt::StructOpt;
 
@@ -15,34 +16,81 @@ use structopt::StructOpt;
     name = "kvs-server",
     about = "The redis server implementation for accessing the store over a network."
 )]
-struct KvsServer {
-    #[structopt(subcommand)]
-    command: Command,
+struct ServerCli {
     #[structopt(flatten)]
     options: Options,
+    #[structopt(flatten)]
+    server_options: ServerOptions,
 }
 
-#[derive(StructOpt, Debug)]
-enum Command {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
-}
-
-fn main() -> Result<()> {
-    stderrlog::new().module(module_path!()).init().unwrap();
-    let config = KvsServer::from_args();
+fn main() {
+    stderrlog::new()
+        .module(module_path!())
+        .module("kvs")
+        .verbosity(1)
+        .init()
+        .unwrap();
+    let config = ServerCli::from_args();
 
     warn!("KvsServer version: {}", env!("CARGO_PKG_VERSION"));
     warn!("Listening on port: {:?}", config.options.socket);
     warn!("Running on engine: {}", config.options.engine);
+    warn!(
+        "Using thread pool: {} with {} threads",
+        config.server_options.thread_pool, config.server_options.threads
+    );
 
-    let mut store = KvStore::open(current_dir()?)?;
-    let mut exit_code = 0;
+    if let Err(error) = run(config) {
+        error!("{}", error);
+        exit(1);
+    }
+}
 
-    match config.command {
-        _ => warn!("some shit going on"),
+fn run(config: ServerCli) -> Result<()> {
+    let ServerCli {
+        options,
+        server_options,
+    } = config;
+    let directory = current_dir()?;
+    options.engine.claim(&directory)?;
+    match options.engine {
+        Engine::Kvs => {
+            let durability = server_options.durability.unwrap_or_default();
+            warn!("Using durability: {}", durability);
+            let store = KvStoreOptions::new()
+                .durability(durability)
+                .open(directory)?;
+            run_with_engine(store, &options, &server_options)
+        }
+        Engine::Sled => {
+            let durability =
+                server_options.durability.unwrap_or(Durability::Always);
+            warn!("Using durability: {}", durability);
+            let engine =
+                SledKvsEngine::open_with_durability(directory, durability)?;
+            run_with_engine(engine, &options, &server_options)
+        }
     }
+}
 
-    exit(exit_code)
+fn run_with_engine<E: KvsEngine>(
+    engine: E,
+    options: &Options,
+    server_options: &ServerOptions,
+) -> Result<()> {
+    let threads = server_options.threads;
+    match server_options.thread_pool {
+        ThreadPoolType::Naive => {
+            KvsServer::new(engine, NaiveThreadPool::new(threads)?)
+                .run(options.socket)
+        }
+        ThreadPoolType::SharedQueue => {
+            KvsServer::new(engine, SharedQueueThreadPool::new(threads)?)
+                .run(options.socket)
+        }
+        ThreadPoolType::Rayon => {
+            KvsServer::new(engine, RayonThreadPool::new(threads)?)
+                .run(options.socket)
+        }
+    }
 }
@@ -0,0 +1,347 @@
+//! # Client
+//! This module contains the network client used to talk to a running
+//! `kvs-server`.
+
+use crate::protocol::{decode_message, read_frame, write_message};
+use crate::{
+    prefix_range, KvsError, Request, Response, Result, ScanOptions, WriteBatch,
+};
+use std::io::{self, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream};
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// A connection to a `kvs-server`.
+///
+/// A single client may be used for any number of requests; each call
+/// blocks until the server's response has been received.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    payload: Vec<u8>,
+}
+
+impl KvsClient {
+    /// Connects to the server listening on `addr`.
+    pub fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            payload: Vec::new(),
+        })
+    }
+
+    /// Retrieves the value stored for `key`, if any.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        match self.send(&Request::Get { key })? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Stores `value` for `key` on the server.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        match self.send(&Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Stores `value` for `key` on the server, expiring it once `ttl` has
+    /// passed.
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        match self.send(&Request::SetWithTtl { key, value, ttl })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Makes an existing `key` on the server expire once `ttl` has passed.
+    pub fn expire_bytes(&mut self, key: &[u8], ttl: Duration) -> Result<()> {
+        let key = key.to_vec();
+        match self.send(&Request::Expire { key, ttl })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Returns the time left before `key` expires on the server, or `None`
+    /// if it never does.
+    pub fn ttl_bytes(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        let key = key.to_vec();
+        match self.send(&Request::Ttl { key })? {
+            Response::Ttl(ttl) => Ok(ttl),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Removes `key` from the server's store.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        let key = key.to_vec();
+        match self.send(&Request::Remove { key })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Applies every write in `batch` atomically on the server.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        match self.send(&Request::Batch { batch })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, only if its
+    /// current value on the server is `expected`. Returns whether the write
+    /// took place.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let key = key.to_vec();
+        let expected = expected.map(<[u8]>::to_vec);
+        let new = new.map(<[u8]>::to_vec);
+        match self.send(&Request::CompareAndSwap { key, expected, new })? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Adds `delta` to the integer stored at `key` on the server, returning
+    /// the result.
+    pub fn incr_bytes(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let key = key.to_vec();
+        match self.send(&Request::Incr { key, delta })? {
+            Response::Integer(value) => Ok(value),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Retrieves the pairs whose keys fall within `range`, in key order.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            options,
+        };
+        match self.send(&request)? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Lists the keys that start with `prefix`, in order.
+    pub fn keys_bytes(&mut self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let prefix = prefix.to_vec();
+        match self.send(&Request::Keys { prefix })? {
+            Response::Keys(keys) => Ok(keys),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Returns the number of keys in the server's store.
+    pub fn len(&mut self) -> Result<usize> {
+        match self.send(&Request::Count)? {
+            Response::Count(count) => Ok(count),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Returns whether the server's store holds no keys.
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns whether `key` exists on the server.
+    pub fn contains_key_bytes(&mut self, key: &[u8]) -> Result<bool> {
+        let key = key.to_vec();
+        match self.send(&Request::ContainsKey { key })? {
+            Response::Exists(exists) => Ok(exists),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    /// Retrieves the string stored for `key`, if any.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        self.get_bytes(key.as_ref())?.map(into_string).transpose()
+    }
+
+    /// Stores the string `value` for `key` on the server.
+    pub fn set(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<()> {
+        self.set_bytes(key.as_ref(), value.as_ref())
+    }
+
+    /// Stores the string `value` for `key` on the server, expiring it once
+    /// `ttl` has passed.
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.set_bytes_with_ttl(key.as_ref(), value.as_ref(), ttl)
+    }
+
+    /// Makes an existing `key` on the server expire once `ttl` has passed.
+    pub fn expire(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.expire_bytes(key.as_ref(), ttl)
+    }
+
+    /// Returns the time left before `key` expires on the server, or `None`
+    /// if it never does.
+    pub fn ttl(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.as_ref())
+    }
+
+    /// Removes `key` from the server's store.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.remove_bytes(key.as_ref())
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, only if its
+    /// current value on the server is `expected`. Returns whether the write
+    /// took place.
+    pub fn compare_and_swap<V: AsRef<[u8]>>(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        expected: Option<V>,
+        new: Option<V>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.as_ref(),
+            expected.as_ref().map(V::as_ref),
+            new.as_ref().map(V::as_ref),
+        )
+    }
+
+    /// Sets `key` to `value` only if it does not exist on the server yet.
+    pub fn set_if_absent(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(key.as_ref(), None, Some(value.as_ref()))
+    }
+
+    /// Removes `key` only if its current value on the server is `expected`.
+    pub fn remove_if_equals(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        expected: impl AsRef<[u8]>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(key.as_ref(), Some(expected.as_ref()), None)
+    }
+
+    /// Adds `delta` to the integer stored at `key` on the server, returning
+    /// the result.
+    pub fn incr(&mut self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64> {
+        self.incr_bytes(key.as_ref(), delta)
+    }
+
+    /// Subtracts `delta` from the integer stored at `key` on the server,
+    /// returning the result.
+    pub fn decr(&mut self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64> {
+        let delta =
+            delta
+                .checked_neg()
+                .ok_or_else(|| KvsError::IntegerOverflow {
+                    key: String::from_utf8_lossy(key.as_ref()).into_owned(),
+                })?;
+        self.incr(key, delta)
+    }
+
+    /// Retrieves the string pairs whose keys fall within `range`, in key
+    /// order.
+    pub fn scan<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (
+            range.start_bound().cloned().map(String::into_bytes),
+            range.end_bound().cloned().map(String::into_bytes),
+        );
+        self.scan_bytes(range, options)?
+            .into_iter()
+            .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+            .collect()
+    }
+
+    /// Retrieves the string pairs whose keys start with `prefix`, in key
+    /// order.
+    pub fn scan_prefix(
+        &mut self,
+        prefix: &str,
+        options: ScanOptions,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan(prefix_range(prefix), options)
+    }
+
+    /// Lists the string keys that start with `prefix`, in order.
+    pub fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        self.keys_bytes(prefix.as_bytes())?
+            .into_iter()
+            .map(into_string)
+            .collect()
+    }
+
+    /// Returns whether `key` exists on the server.
+    pub fn contains_key(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        self.contains_key_bytes(key.as_ref())
+    }
+
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.writer, request)?;
+        // Responses are not limited, since they only hold what was asked
+        // for.
+        if !read_frame(&mut self.reader, &mut self.payload, u32::MAX)? {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Server closed the connection",
+            )
+            .into());
+        }
+        match decode_message(&self.payload)? {
+            Response::Error { code, message } => {
+                Err(KvsError::from_server(code, message))
+            }
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected_response(response: Response) -> KvsError {
+    KvsError::Protocol(format!(
+        "Unexpected response from server: {:?}",
+        response
+    ))
+}
+
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes)?)
+}
@@ -0,0 +1,76 @@
+use crate::Entry;
+use serde::{Deserialize, Serialize};
+use std::vec;
+
+/// A group of writes that an engine applies atomically: after a crash,
+/// either all of them are visible or none are.
+///
+/// Writes are applied in the order they were added. Removing a key that
+/// does not exist is not an error within a batch.
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set(String::from("from"), String::from("90"))
+///     .set(String::from("to"), String::from("110"))
+///     .remove(String::from("transfer"));
+/// store.write_batch(batch).unwrap();
+/// assert_eq!(store.get(String::from("to")).unwrap(), Some(String::from("110")));
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    entries: Vec<Entry>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds a write setting `key` to `value`.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.entries.push(Entry::set(key, value));
+        self
+    }
+
+    /// Adds a write removing `key`.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> &mut Self {
+        self.entries.push(Entry::rm(key));
+        self
+    }
+
+    /// Adds a write setting the string `key` to `value`.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Adds a write removing the string `key`.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = Entry;
+    type IntoIter = vec::IntoIter<Entry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
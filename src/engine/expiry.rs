@@ -0,0 +1,19 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Returns the current time in milliseconds since the Unix epoch, the unit
+/// expiry times are stored in.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Returns the expiry time of a key that should live for `ttl` from now.
+pub fn expires_at(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Returns how long is left until `expires_at`.
+pub fn time_left(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now()))
+}
@@ -1,7 +1,266 @@
-use crate::Result;
+use crate::{KvsError, Result};
+use std::ops::RangeBounds;
+use std::str;
+use std::time::Duration;
 
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+mod batch;
+pub(crate) mod expiry;
+mod scan;
+mod sled;
+
+pub use self::batch::WriteBatch;
+pub use self::scan::{prefix_range, prefix_range_bytes, Scan, ScanOptions};
+pub use self::sled::SledKvsEngine;
+
+/// The interface shared by every storage backend `kvs-server` can run on.
+///
+/// Engines are cheap to clone and every clone refers to the same
+/// underlying store, so a server can hand one clone to each thread
+/// serving requests. Implementations synchronize internally, which is
+/// why every operation only needs `&self`.
+///
+/// Keys and values are arbitrary bytes, handled by the methods ending in
+/// `_bytes`, which borrow them. The remaining methods are a convenience
+/// layer over those: they accept keys as anything that can be viewed as
+/// bytes, such as `&str` or `String`, and return strings. Reading a key or
+/// value that is not valid UTF-8 through them is an error.
+///
+/// `get_into` reads a value into a buffer owned by the caller, so a loop
+/// of lookups can reuse one buffer instead of allocating for each value.
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine};
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+///
+/// store.set_bytes(&[0, 159, 146, 150], &[255, 0]).unwrap();
+/// let value = store.get_bytes(&[0, 159, 146, 150]).unwrap();
+/// assert_eq!(value, Some(vec![255, 0]));
+///
+/// store.set("greeting", "hello").unwrap();
+/// let mut buffer = Vec::new();
+/// assert!(store.get_into(b"greeting", &mut buffer).unwrap());
+/// assert_eq!(buffer, b"hello");
+/// ```
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of `key` to `value`, overwriting any previous value
+    /// along with its time-to-live.
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()>;
+    /// Sets the value of `key` to `value`, to expire once `ttl` has passed.
+    ///
+    /// Expired keys are hidden from every read as soon as they expire.
+    fn set_bytes_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Makes the existing `key` expire once `ttl` has passed, returning an
+    /// error if it does not exist.
+    fn expire_bytes(&self, key: &[u8], ttl: Duration) -> Result<()>;
+    /// Returns how long `key` has left to live, or `None` if it does not
+    /// expire. Returns an error if it does not exist.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>>;
+    /// Replaces the contents of `value` with the value of `key`, returning
+    /// whether the key exists. `value` is left empty if it does not.
+    fn get_into(&self, key: &[u8], value: &mut Vec<u8>) -> Result<bool>;
+    /// Removes `key`, returning an error if it does not exist.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+    /// Applies every write in `batch` atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Sets `key` to `new`, or removes it if `new` is `None`, but only if
+    /// its current value is `expected`, where `None` means the key does not
+    /// exist. Returns whether the write happened.
+    ///
+    /// The comparison and the write are atomic with respect to every other
+    /// write to the store.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool>;
+    /// Adds `delta` to the integer stored at `key` and returns the result,
+    /// treating a missing key as `0`. Fails if the stored value is not an
+    /// integer, written out in ASCII, or the result overflows an `i64`. The
+    /// key keeps its time-to-live.
+    ///
+    /// The read and the write are atomic with respect to every other write
+    /// to the store.
+    fn incr_bytes(&self, key: &[u8], delta: i64) -> Result<i64>;
+    /// Iterates over the pairs whose keys fall within `range`, in
+    /// lexicographic byte order.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan<Vec<u8>>>;
+    /// Lists the keys that start with `prefix`, in order, without reading
+    /// their values.
+    fn keys_bytes(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>>;
+    /// Returns the number of keys in the store.
+    fn len(&self) -> Result<usize>;
+    /// Returns whether `key` exists, without reading its value.
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool>;
+
+    /// Retrieves the value of `key`, or `None` if it does not exist.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut value = Vec::new();
+        Ok(self.get_into(key, &mut value)?.then_some(value))
+    }
+    /// Iterates over the pairs whose keys start with `prefix`, in order.
+    fn scan_prefix_bytes(
+        &self,
+        prefix: &[u8],
+        options: ScanOptions,
+    ) -> Result<Scan<Vec<u8>>> {
+        self.scan_bytes(prefix_range_bytes(prefix), options)
+    }
+    /// Returns whether the store holds no keys.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Sets the value of `key` to `value`, as `set_bytes` does.
+    fn set(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<()> {
+        self.set_bytes(key.as_ref(), value.as_ref())
+    }
+    /// Sets the value of `key` to expire, as `set_bytes_with_ttl` does.
+    fn set_with_ttl(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.set_bytes_with_ttl(key.as_ref(), value.as_ref(), ttl)
+    }
+    /// Makes the existing `key` expire, as `expire_bytes` does.
+    fn expire(&self, key: impl AsRef<[u8]>, ttl: Duration) -> Result<()> {
+        self.expire_bytes(key.as_ref(), ttl)
+    }
+    /// Returns how long `key` has left to live, as `ttl_bytes` does.
+    fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.as_ref())
+    }
+    /// Retrieves the value of `key` as a string, as `get_bytes` does.
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        self.get_bytes(key.as_ref())?.map(into_string).transpose()
+    }
+    /// Removes `key`, as `remove_bytes` does.
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.remove_bytes(key.as_ref())
+    }
+    /// Sets or removes `key` if its current value is `expected`, as
+    /// `compare_and_swap_bytes` does.
+    fn compare_and_swap<V: AsRef<[u8]>>(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<V>,
+        new: Option<V>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.as_ref(),
+            expected.as_ref().map(V::as_ref),
+            new.as_ref().map(V::as_ref),
+        )
+    }
+    /// Sets `key` to `value` only if it does not exist yet, returning
+    /// whether it did.
+    fn set_if_absent(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(key.as_ref(), None, Some(value.as_ref()))
+    }
+    /// Removes `key` only if its current value is `expected`, returning
+    /// whether it did.
+    fn remove_if_equals(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: impl AsRef<[u8]>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(key.as_ref(), Some(expected.as_ref()), None)
+    }
+    /// Adds `delta` to the integer stored at `key`, as `incr_bytes` does.
+    fn incr(&self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64> {
+        self.incr_bytes(key.as_ref(), delta)
+    }
+    /// Subtracts `delta` from the integer stored at `key`, as `incr` does.
+    fn decr(&self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64> {
+        let delta =
+            delta
+                .checked_neg()
+                .ok_or_else(|| KvsError::IntegerOverflow {
+                    key: String::from_utf8_lossy(key.as_ref()).into_owned(),
+                })?;
+        self.incr(key, delta)
+    }
+    /// Iterates over the pairs whose keys fall within `range`, in key
+    /// order, as `scan_bytes` does.
+    fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan> {
+        let range = (
+            range.start_bound().cloned().map(String::into_bytes),
+            range.end_bound().cloned().map(String::into_bytes),
+        );
+        let pairs = self.scan_bytes(range, options)?;
+        Ok(Box::new(pairs.map(|pair| {
+            let (key, value) = pair?;
+            Ok((into_string(key)?, into_string(value)?))
+        })))
+    }
+    /// Iterates over the pairs whose keys start with `prefix`, in key
+    /// order.
+    fn scan_prefix(&self, prefix: &str, options: ScanOptions) -> Result<Scan> {
+        self.scan(prefix_range(prefix), options)
+    }
+    /// Lists the keys that start with `prefix`, as `keys_bytes` does.
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.keys_bytes(prefix.as_bytes())?
+            .into_iter()
+            .map(into_string)
+            .collect()
+    }
+    /// Returns whether `key` exists, as `contains_key_bytes` does.
+    fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        self.contains_key_bytes(key.as_ref())
+    }
+}
+
+/// Decodes a key or value read through the string API.
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Adds `delta` to `value`, the current value of `key`, for `incr`.
+pub(crate) fn add_delta(
+    key: &[u8],
+    value: Option<&[u8]>,
+    delta: i64,
+) -> Result<i64> {
+    let key = String::from_utf8_lossy(key);
+    let current = match value {
+        Some(value) => str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(|| KvsError::NotAnInteger {
+                key: key.to_string(),
+                value: String::from_utf8_lossy(value).into_owned(),
+            })?,
+        None => 0,
+    };
+    current
+        .checked_add(delta)
+        .ok_or_else(|| KvsError::IntegerOverflow {
+            key: key.into_owned(),
+        })
 }
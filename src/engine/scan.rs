@@ -0,0 +1,96 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+
+/// An ordered iterator over the key/value pairs matched by a scan, as
+/// strings or, for `Scan<Vec<u8>>`, as raw bytes.
+///
+/// Values are read as the iterator advances, so reading one may fail
+/// independently of the others.
+pub type Scan<T = String> = Box<dyn Iterator<Item = Result<(T, T)>> + Send>;
+
+/// Settings for a range or prefix scan.
+///
+/// ```rust
+/// use kvs::ScanOptions;
+/// let options = ScanOptions::new().limit(10).reverse();
+/// ```
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct ScanOptions {
+    /// The maximum number of pairs to return, or `None` for all of them.
+    pub limit: Option<usize>,
+    /// Whether pairs are returned in descending key order.
+    pub reverse: bool,
+}
+
+impl ScanOptions {
+    /// Creates options that return every matching pair in ascending key
+    /// order.
+    pub fn new() -> Self {
+        ScanOptions::default()
+    }
+
+    /// Returns at most `limit` pairs.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns pairs in descending key order, starting from the end of the
+    /// range.
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+}
+
+/// Returns the range of keys that start with `prefix`.
+///
+/// ```rust
+/// use kvs::prefix_range;
+/// use std::ops::{Bound, RangeBounds};
+/// let range = prefix_range("user:");
+/// assert!(range.contains(&String::from("user:42")));
+/// assert!(!range.contains(&String::from("users")));
+/// ```
+pub fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let start = Bound::Included(prefix.to_owned());
+    // The first string after every string with the prefix is the prefix
+    // with its last character incremented. Characters that cannot be
+    // incremented are dropped, and if none are left the range is open.
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        let next = (u32::from(last) + 1..=u32::from(char::MAX))
+            .find_map(std::char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return (start, Bound::Excluded(end));
+        }
+    }
+    (start, Bound::Unbounded)
+}
+
+/// Returns the range of byte keys that start with `prefix`.
+///
+/// ```rust
+/// use kvs::prefix_range_bytes;
+/// use std::ops::{Bound, RangeBounds};
+/// let range = prefix_range_bytes(&[1, 255]);
+/// assert!(range.contains(&vec![1, 255, 0]));
+/// assert_eq!(range.1, Bound::Excluded(vec![2]));
+/// ```
+pub fn prefix_range_bytes(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = Bound::Included(prefix.to_vec());
+    // As with strings, but bytes of 255 are the ones that cannot be
+    // incremented.
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (start, Bound::Excluded(end));
+        }
+    }
+    (start, Bound::Unbounded)
+}
@@ -0,0 +1,311 @@
+use crate::engine::{add_delta, expiry};
+use crate::{
+    Durability, Entry, KvsEngine, KvsError, Result, Scan, ScanOptions,
+    WriteBatch,
+};
+use sled::{Db, IVec};
+use std::convert::TryFrom;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Marks a stored value that carries an expiry time.
+const EXPIRY_MARKER: u8 = 0xFF;
+const EXPIRY_HEADER_LENGTH: usize = 9;
+
+/// A `KvsEngine` backed by the `sled` embedded database.
+///
+/// Unless opened with another `Durability`, every write is flushed to
+/// disk before it returns.
+#[derive(Clone, Debug)]
+pub struct SledKvsEngine {
+    db: Db,
+    durability: Durability,
+    unsynced_writes: Arc<AtomicU32>,
+}
+
+impl SledKvsEngine {
+    /// Wraps an already opened `sled` database, flushing every write.
+    pub fn new(db: Db) -> Self {
+        SledKvsEngine::with_durability(db, Durability::Always)
+    }
+
+    /// Opens the `sled` database stored in the `.sled` directory
+    /// under `path`, creating it if it does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        SledKvsEngine::open_with_durability(path, Durability::Always)
+    }
+
+    /// Opens the `sled` database like `open`, flushing writes as
+    /// `durability` asks.
+    ///
+    /// `sled` buffers writes in memory until they are flushed, so unlike
+    /// with `KvStore`, writes that were not flushed yet are also lost if
+    /// the process crashes. With `Durability::Never`, they are flushed by
+    /// `sled`'s own background thread, every 500ms.
+    pub fn open_with_durability(
+        path: impl Into<PathBuf>,
+        durability: Durability,
+    ) -> Result<Self> {
+        let mut path_buf: PathBuf = path.into();
+        path_buf.push(".sled");
+        let mut config = sled::Config::new().path(path_buf);
+        if let Durability::Interval(interval) = durability {
+            let interval = u64::try_from(interval.as_millis())
+                .unwrap_or(u64::MAX)
+                .max(1);
+            config = config.flush_every_ms(Some(interval));
+        }
+        Ok(SledKvsEngine::with_durability(config.open()?, durability))
+    }
+
+    fn with_durability(db: Db, durability: Durability) -> Self {
+        SledKvsEngine {
+            db,
+            durability,
+            unsynced_writes: Arc::new(AtomicU32::new(0)),
+        }
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.insert(key, encode_value(value, None))?;
+        self.commit()
+    }
+
+    fn get_into(&self, key: &[u8], value: &mut Vec<u8>) -> Result<bool> {
+        let stored = self.db.get(key)?;
+        value.clear();
+        match live_value(stored.as_deref()) {
+            Some((stored, _)) => {
+                value.extend_from_slice(stored);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let removed = self.db.remove(key)?;
+        self.commit()?;
+        live_value(removed.as_deref()).ok_or(KvsError::KeyNotFound)?;
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        for entry in batch {
+            match entry {
+                Entry::Set(key, value) => {
+                    sled_batch.insert(key, encode_value(&value, None))
+                }
+                Entry::SetWithExpiry(key, value, expires_at) => sled_batch
+                    .insert(key, encode_value(&value, Some(expires_at))),
+                Entry::Rm(key) => sled_batch.remove(key),
+            }
+        }
+        self.db.apply_batch(sled_batch)?;
+        self.commit()
+    }
+
+    /// Compares against the stored value, then swaps the raw bytes it was
+    /// read from, retrying if another write came in between.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        loop {
+            let current = self.db.get(key)?;
+            let value = live_value(current.as_deref());
+            if value.map(|(value, _)| value) != expected {
+                return Ok(false);
+            }
+            let new = new.map(|new| encode_value(new, None));
+            if self.swap(key, current, new)? {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Adds `delta` to the integer stored at `key`, retrying until no
+    /// other write has come in between.
+    fn incr_bytes(&self, key: &[u8], delta: i64) -> Result<i64> {
+        loop {
+            let current = self.db.get(key)?;
+            let (value, expires_at) = live_value(current.as_deref()).unzip();
+            let value = add_delta(key, value, delta)?;
+            let new = encode_value(
+                value.to_string().as_bytes(),
+                expires_at.flatten(),
+            );
+            if self.swap(key, current, Some(new))? {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn set_bytes_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        self.db.insert(key, encode_value(value, Some(expires_at)))?;
+        self.commit()
+    }
+
+    fn expire_bytes(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        loop {
+            let current = self.db.get(key)?;
+            let (value, _) =
+                live_value(current.as_deref()).ok_or(KvsError::KeyNotFound)?;
+            let new = encode_value(value, Some(expires_at));
+            if self.swap(key, current, Some(new))? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let (_, expires_at) = live_value(self.db.get(key)?.as_deref())
+            .ok_or(KvsError::KeyNotFound)?;
+        Ok(expires_at.map(expiry::time_left))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan<Vec<u8>>> {
+        let pairs = self.db.range(range);
+        let pairs: Scan<Vec<u8>> = if options.reverse {
+            Box::new(pairs.rev().filter_map(decode_pair))
+        } else {
+            Box::new(pairs.filter_map(decode_pair))
+        };
+        Ok(match options.limit {
+            Some(limit) => Box::new(pairs.take(limit)),
+            None => pairs,
+        })
+    }
+
+    fn keys_bytes(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.db
+            .scan_prefix(prefix)
+            .filter_map(decode_pair)
+            .map(|pair| Ok(pair?.0))
+            .collect()
+    }
+
+    /// Counts the keys in the store. Unlike `KvStore`, `sled` does not
+    /// keep a count, so this walks every key, skipping expired ones.
+    fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        for pair in self.db.iter().filter_map(decode_pair) {
+            pair?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        Ok(live_value(self.db.get(key)?.as_deref()).is_some())
+    }
+}
+
+impl SledKvsEngine {
+    /// Replaces the raw bytes at `key` with `new` if they are still
+    /// `current`, returning whether they were.
+    fn swap(
+        &self,
+        key: &[u8],
+        current: Option<IVec>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let swapped = self.db.compare_and_swap(key, current, new)?.is_ok();
+        if swapped {
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+
+    /// Flushes a write that just completed if the durability setting calls
+    /// for it.
+    fn commit(&self) -> Result<()> {
+        let flush = match self.durability {
+            Durability::Always => true,
+            Durability::Writes(writes) => {
+                let unsynced =
+                    self.unsynced_writes.fetch_add(1, Ordering::SeqCst) + 1;
+                unsynced >= writes
+                    && self
+                        .unsynced_writes
+                        .compare_exchange(
+                            unsynced,
+                            0,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        )
+                        .is_ok()
+            }
+            Durability::Interval(..) | Durability::Never => false,
+        };
+        if flush {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a value for storage. A value with an expiry is prefixed with
+/// `EXPIRY_MARKER` and the expiry time. So is a value without one that
+/// happens to start with the marker byte, with an expiry time of `0`.
+/// Values written before expiry times existed were all UTF-8, which never
+/// starts with the marker byte, so they decode as they are.
+fn encode_value(value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(EXPIRY_HEADER_LENGTH + value.len());
+    if expires_at.is_some() || value.first() == Some(&EXPIRY_MARKER) {
+        bytes.push(EXPIRY_MARKER);
+        bytes.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    }
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// Decodes a stored value and its expiry time, returning `None` if there
+/// is no value or it has expired.
+fn live_value(bytes: Option<&[u8]>) -> Option<(&[u8], Option<u64>)> {
+    let bytes = bytes?;
+    let (value, expires_at) = match bytes.first() {
+        Some(&EXPIRY_MARKER) if bytes.len() >= EXPIRY_HEADER_LENGTH => {
+            let (header, value) = bytes.split_at(EXPIRY_HEADER_LENGTH);
+            let mut expires_at = [0; 8];
+            expires_at.copy_from_slice(&header[1..]);
+            let expires_at = u64::from_le_bytes(expires_at);
+            (value, Some(expires_at).filter(|&at| at != 0))
+        }
+        _ => (bytes, None),
+    };
+    if expires_at.is_some_and(|expires_at| expires_at <= expiry::now()) {
+        return None;
+    }
+    Some((value, expires_at))
+}
+
+/// Decodes a stored pair, skipping it if it has expired.
+fn decode_pair(
+    pair: sled::Result<(IVec, IVec)>,
+) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+    match pair {
+        Ok((key, value)) => live_value(Some(&value))
+            .map(|(value, _)| Ok((key.to_vec(), value.to_vec()))),
+        Err(error) => Some(Err(error.into())),
+    }
+}
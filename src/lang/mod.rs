@@ -42,6 +42,22 @@ macro_rules! successful_get_without_result {
     };
 }
 
+/// error message literal
+#[macro_export]
+macro_rules! successful_scan_pair {
+    () => {
+        "{}\t{}"
+    };
+}
+
+/// error message literal
+#[macro_export]
+macro_rules! successful_ttl_without_expiry {
+    () => {
+        "No expiry"
+    };
+}
+
 /// error message literal
 #[macro_export]
 macro_rules! kvs_error {
@@ -1,9 +1,21 @@
+mod client;
 mod engine;
 mod lang;
 mod options;
+mod protocol;
+mod server;
 mod store;
+mod thread_pool;
 
-pub use engine::KvsEngine;
-pub use lang::*;
-pub use options::Options;
+pub use client::KvsClient;
+pub use engine::{
+    prefix_range, prefix_range_bytes, KvsEngine, Scan, ScanOptions,
+    SledKvsEngine, WriteBatch,
+};
+pub use options::{Engine, Options, ServerOptions, ThreadPoolType};
+pub use protocol::{ErrorCode, Request, Response};
+pub use server::KvsServer;
 pub use store::*;
+pub use thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
+};
@@ -1,4 +1,9 @@
+use crate::{Durability, KvsError, Result};
+use std::fmt;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -7,9 +12,173 @@ pub struct Options {
         long = "addr",
         help = "Sets the server address",
         default_value = "127.0.0.1:4000",
+        global = true,
         parse(try_from_str)
     )]
     pub socket: SocketAddr,
-    #[structopt(default_value = "kvs", long = "engine")]
-    pub engine: String,
+    #[structopt(
+        long = "engine",
+        help = "Sets the storage engine",
+        default_value = "kvs",
+        possible_values = &Engine::VARIANTS,
+        global = true
+    )]
+    pub engine: Engine,
+}
+
+/// The options only `kvs-server` accepts, on top of `Options`.
+#[derive(StructOpt, Debug)]
+pub struct ServerOptions {
+    #[structopt(
+        long = "thread-pool",
+        help = "Sets the thread pool used to serve connections",
+        default_value = "shared-queue",
+        possible_values = &ThreadPoolType::VARIANTS
+    )]
+    pub thread_pool: ThreadPoolType,
+    #[structopt(
+        long = "threads",
+        help = "Sets the number of threads in the thread pool",
+        default_value = "4",
+        parse(try_from_str = parse_threads)
+    )]
+    pub threads: u32,
+    #[structopt(
+        long = "durability",
+        help = "Sets when writes are synced to disk: always, never, <N>writes, or <N>ms [default: never for kvs, always for sled]"
+    )]
+    pub durability: Option<Durability>,
+}
+
+/// Parses the number of threads in the thread pool, which must not be 0.
+fn parse_threads(threads: &str) -> Result<u32> {
+    match threads.parse::<u32>()? {
+        0 => Err(no_threads()),
+        threads => Ok(threads),
+    }
+}
+
+/// The error returned for a thread pool with no threads.
+pub(crate) fn no_threads() -> KvsError {
+    KvsError::InvalidInput(
+        "The thread pool needs at least one thread".to_owned(),
+    )
+}
+
+const ENGINE_MARKER: &str = ".engine";
+
+/// The storage engines a `kvs-server` can run on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    /// The log-structured `KvStore` implemented by this crate
+    Kvs,
+    /// The `sled` embedded database
+    Sled,
+}
+
+impl Engine {
+    /// The names accepted when parsing an `Engine`.
+    pub const VARIANTS: [&'static str; 2] = ["kvs", "sled"];
+
+    /// Records this engine as the owner of `directory`, or verifies that
+    /// it already is.
+    ///
+    /// The owning engine is persisted in a marker file the first time a
+    /// directory is claimed. Directories created before the marker existed
+    /// are recognized by the data directory each engine creates.
+    pub fn claim(self, directory: &Path) -> Result<()> {
+        let marker_path = directory.join(ENGINE_MARKER);
+        match Engine::persisted(directory)? {
+            Some(persisted) if persisted != self => {
+                Err(KvsError::WrongEngine {
+                    path: directory.to_path_buf(),
+                    found: persisted,
+                    requested: self,
+                })
+            }
+            Some(_) if marker_path.exists() => Ok(()),
+            _ => {
+                fs::write(marker_path, self.to_string()).map_err(KvsError::from)
+            }
+        }
+    }
+
+    fn persisted(directory: &Path) -> Result<Option<Engine>> {
+        let marker_path = directory.join(ENGINE_MARKER);
+        if marker_path.exists() {
+            fs::read_to_string(marker_path)?.trim().parse().map(Some)
+        } else if directory.join(".kvs").exists() {
+            Ok(Some(Engine::Kvs))
+        } else if directory.join(".sled").exists() {
+            Ok(Some(Engine::Sled))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = KvsError;
+
+    fn from_str(engine: &str) -> Result<Self> {
+        match engine {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            _ => Err(KvsError::InvalidInput(format!(
+                "Unknown engine: {}",
+                engine
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Engine::Kvs => write!(formatter, "kvs"),
+            Engine::Sled => write!(formatter, "sled"),
+        }
+    }
+}
+
+/// The thread pools a `kvs-server` can serve connections with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadPoolType {
+    /// `NaiveThreadPool`, which spawns a thread per connection
+    Naive,
+    /// `SharedQueueThreadPool`, a fixed set of workers sharing one queue
+    SharedQueue,
+    /// `RayonThreadPool`, rayon's work-stealing pool
+    Rayon,
+}
+
+impl ThreadPoolType {
+    /// The names accepted when parsing a `ThreadPoolType`.
+    pub const VARIANTS: [&'static str; 3] = ["naive", "shared-queue", "rayon"];
+}
+
+impl FromStr for ThreadPoolType {
+    type Err = KvsError;
+
+    fn from_str(thread_pool: &str) -> Result<Self> {
+        match thread_pool {
+            "naive" => Ok(ThreadPoolType::Naive),
+            "shared-queue" => Ok(ThreadPoolType::SharedQueue),
+            "rayon" => Ok(ThreadPoolType::Rayon),
+            _ => Err(KvsError::InvalidInput(format!(
+                "Unknown thread pool: {}",
+                thread_pool
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ThreadPoolType {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThreadPoolType::Naive => write!(formatter, "naive"),
+            ThreadPoolType::SharedQueue => write!(formatter, "shared-queue"),
+            ThreadPoolType::Rayon => write!(formatter, "rayon"),
+        }
+    }
 }
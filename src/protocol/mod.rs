@@ -0,0 +1,283 @@
+//! # Protocol
+//! This module defines the messages exchanged between `kvs-client`
+//! and `kvs-server`.
+//!
+//! Messages are encoded with `bincode` and written back-to-back on the
+//! TCP stream, each as a frame:
+//!
+//! | bytes | contents                                  |
+//! |-------|-------------------------------------------|
+//! | 4     | payload length, little-endian `u32`       |
+//! | n     | payload: the encoded message              |
+//!
+//! A single connection may carry any number of requests. Every request
+//! receives exactly one response. Requests are limited to
+//! `MAX_REQUEST_LENGTH` bytes, so that a client cannot make the server
+//! buffer an arbitrarily large frame.
+//!
+//! Keys and values are carried as raw bytes, each written as its length
+//! followed by the bytes themselves, so they need not be valid UTF-8.
+//!
+//! Failed requests are answered with an `ErrorCode` alongside the message,
+//! so clients can tell kinds of errors apart without parsing messages.
+
+use crate::{KvsError, Result, ScanOptions, WriteBatch};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+use std::time::Duration;
+
+const FRAME_HEADER_LENGTH: usize = 4;
+
+/// The largest request payload the server accepts, in bytes.
+pub(crate) const MAX_REQUEST_LENGTH: u32 = 64 * 1024 * 1024;
+
+/// A request sent from a client to the server.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    /// Retrieves the value stored for `key`.
+    Get {
+        /// The key to look up
+        key: Vec<u8>,
+    },
+    /// Stores `value` for `key`, overwriting any existing value.
+    Set {
+        /// The key to store
+        key: Vec<u8>,
+        /// The value to store
+        value: Vec<u8>,
+    },
+    /// Stores `value` for `key`, expiring it once `ttl` has passed.
+    SetWithTtl {
+        /// The key to store
+        key: Vec<u8>,
+        /// The value to store
+        value: Vec<u8>,
+        /// How long the key lives for
+        ttl: Duration,
+    },
+    /// Makes an existing `key` expire once `ttl` has passed.
+    Expire {
+        /// The key to expire
+        key: Vec<u8>,
+        /// How long the key lives for
+        ttl: Duration,
+    },
+    /// Retrieves the time left before `key` expires.
+    Ttl {
+        /// The key to look up
+        key: Vec<u8>,
+    },
+    /// Removes `key` from the store.
+    Remove {
+        /// The key to remove
+        key: Vec<u8>,
+    },
+    /// Applies every write in `batch` atomically.
+    Batch {
+        /// The writes to apply
+        batch: WriteBatch,
+    },
+    /// Sets `key` to `new`, or removes it if `new` is `None`, only if its
+    /// current value is `expected`.
+    CompareAndSwap {
+        /// The key to update
+        key: Vec<u8>,
+        /// The value the key must currently have, or `None` if it must
+        /// not exist
+        expected: Option<Vec<u8>>,
+        /// The value to store, or `None` to remove the key
+        new: Option<Vec<u8>>,
+    },
+    /// Adds `delta` to the integer stored at `key`.
+    Incr {
+        /// The key holding the integer
+        key: Vec<u8>,
+        /// The amount to add, which may be negative
+        delta: i64,
+    },
+    /// Retrieves the pairs whose keys fall between `start` and `end`.
+    Scan {
+        /// The lower bound of the range
+        start: Bound<Vec<u8>>,
+        /// The upper bound of the range
+        end: Bound<Vec<u8>>,
+        /// The limit and order of the pairs returned
+        options: ScanOptions,
+    },
+    /// Lists the keys that start with `prefix`.
+    Keys {
+        /// The prefix to match
+        prefix: Vec<u8>,
+    },
+    /// Counts the keys in the store.
+    Count,
+    /// Checks whether `key` exists.
+    ContainsKey {
+        /// The key to look for
+        key: Vec<u8>,
+    },
+}
+
+/// The server's reply to a single `Request`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// The request succeeded and has no value to return.
+    Ok,
+    /// The value stored for the requested key.
+    Value(Vec<u8>),
+    /// The requested key does not exist in the store.
+    NotFound,
+    /// The key/value pairs matched by a scan, in the requested order.
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    /// The keys matched by a `Keys` request, in order.
+    Keys(Vec<Vec<u8>>),
+    /// The number of keys in the store.
+    Count(usize),
+    /// Whether the requested key exists.
+    Exists(bool),
+    /// Whether a conditional write took place.
+    Swapped(bool),
+    /// The integer stored after an `Incr`.
+    Integer(i64),
+    /// The time left before the requested key expires, or `None` if it
+    /// never does.
+    Ttl(Option<Duration>),
+    /// The request failed.
+    Error {
+        /// The kind of error the request failed with
+        code: ErrorCode,
+        /// A description of the error
+        message: String,
+    },
+}
+
+/// The kind of error a request failed with, as sent over the network.
+///
+/// Codes are sent as numbers that never change meaning, so they can be
+/// matched on across versions. A code unknown to the receiving side is read
+/// as `Other`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    /// An error with no code of its own
+    Other = 0,
+    /// The key does not exist
+    KeyNotFound = 1,
+    /// Reading or writing a file or socket failed
+    Io = 2,
+    /// A message or log entry could not be encoded or decoded
+    Serialization = 3,
+    /// A log segment is corrupted or in an unsupported format
+    Corruption = 4,
+    /// The store is held open by another writer
+    Locked = 5,
+    /// The store belongs to a different engine
+    WrongEngine = 6,
+    /// A message did not follow the protocol
+    Protocol = 7,
+    /// The store is read-only
+    ReadOnly = 8,
+    /// The value being incremented is not an integer
+    NotAnInteger = 9,
+    /// Incrementing or decrementing overflowed
+    IntegerOverflow = 10,
+    /// A key or value is not valid UTF-8
+    InvalidUtf8 = 11,
+    /// The storage engine failed
+    Storage = 12,
+    /// An argument given by the user is not valid
+    InvalidInput = 13,
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> u16 {
+        code as u16
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> ErrorCode {
+        match code {
+            1 => ErrorCode::KeyNotFound,
+            2 => ErrorCode::Io,
+            3 => ErrorCode::Serialization,
+            4 => ErrorCode::Corruption,
+            5 => ErrorCode::Locked,
+            6 => ErrorCode::WrongEngine,
+            7 => ErrorCode::Protocol,
+            8 => ErrorCode::ReadOnly,
+            9 => ErrorCode::NotAnInteger,
+            10 => ErrorCode::IntegerOverflow,
+            11 => ErrorCode::InvalidUtf8,
+            12 => ErrorCode::Storage,
+            13 => ErrorCode::InvalidInput,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+/// Writes `message` as a single frame and flushes `writer`.
+pub(crate) fn write_message<W: Write, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> Result<()> {
+    let payload = bincode::serialize(message)?;
+    let length = u32::try_from(payload.len()).map_err(|_| {
+        KvsError::Protocol(format!(
+            "Message of {} bytes is too long to send",
+            payload.len()
+        ))
+    })?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the payload of the next frame into `payload`, failing without
+/// reading it if it is longer than `max_length`.
+///
+/// Returns `false` if the stream ended cleanly before the frame started.
+pub(crate) fn read_frame<R: Read>(
+    reader: &mut R,
+    payload: &mut Vec<u8>,
+    max_length: u32,
+) -> Result<bool> {
+    let mut header = [0; FRAME_HEADER_LENGTH];
+    let mut header_length = 0;
+    while header_length < FRAME_HEADER_LENGTH {
+        match reader.read(&mut header[header_length..]) {
+            Ok(0) if header_length == 0 => return Ok(false),
+            Ok(0) => return Err(truncated_frame()),
+            Ok(read) => header_length += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    let length = u32::from_le_bytes(header);
+    if length > max_length {
+        return Err(KvsError::Protocol(format!(
+            "Message of {} bytes exceeds the limit of {} bytes",
+            length, max_length
+        )));
+    }
+    let length = u64::from(length);
+    payload.clear();
+    if reader.take(length).read_to_end(payload)? as u64 != length {
+        return Err(truncated_frame());
+    }
+    Ok(true)
+}
+
+/// Decodes a message from the payload of a frame.
+pub(crate) fn decode_message<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(payload)?)
+}
+
+fn truncated_frame() -> KvsError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Message was cut short").into()
+}
@@ -0,0 +1,141 @@
+//! # Server
+//! This module contains the network server that exposes a `KvsEngine`
+//! over TCP using the messages defined in `protocol`.
+
+use crate::protocol::{
+    decode_message, read_frame, write_message, MAX_REQUEST_LENGTH,
+};
+use crate::{ErrorCode, KvsEngine, Request, Response, Result, ThreadPool};
+use log::{debug, error};
+use std::io::{BufReader, BufWriter, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+/// Serves requests for a single `KvsEngine` over TCP.
+///
+/// Each connection is handed to the thread pool together with its own
+/// clone of the engine.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Creates a server backed by the given engine and thread pool.
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer { engine, pool }
+    }
+
+    /// Binds to `addr` and serves incoming connections until the
+    /// process is terminated.
+    ///
+    /// Errors on individual connections are logged and do not stop
+    /// the server.
+    pub fn run(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    self.pool.spawn(move || {
+                        if let Err(error) = serve(&engine, stream) {
+                            error!("Error serving client: {}", error);
+                        }
+                    });
+                }
+                Err(error) => error!("Connection failed: {}", error),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serve<E: KvsEngine>(engine: &E, stream: TcpStream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut payload = Vec::new();
+
+    loop {
+        // Once a request cannot be read, the client and server no longer
+        // agree on the protocol, so the client is told why before the
+        // connection is closed.
+        let request = match read_request(&mut reader, &mut payload) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(error) if error.code() == ErrorCode::Protocol => {
+                let response = Response::Error {
+                    code: ErrorCode::Protocol,
+                    message: format!("Malformed request: {}", error),
+                };
+                write_message(&mut writer, &response)?;
+                return Err(error);
+            }
+            Err(error) => return Err(error),
+        };
+        debug!("Received request from {}: {:?}", peer_addr, request);
+        let response = execute(engine, request);
+        debug!("Sending response to {}: {:?}", peer_addr, response);
+        write_message(&mut writer, &response)?;
+    }
+}
+
+/// Reads the next request, or `None` once the client has closed the
+/// connection.
+fn read_request<R: Read>(
+    reader: &mut R,
+    payload: &mut Vec<u8>,
+) -> Result<Option<Request>> {
+    if !read_frame(reader, payload, MAX_REQUEST_LENGTH)? {
+        return Ok(None);
+    }
+    decode_message(payload).map(Some)
+}
+
+fn execute<E: KvsEngine>(engine: &E, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key } => engine.get_bytes(&key).map(|value| {
+            value.map(Response::Value).unwrap_or(Response::NotFound)
+        }),
+        Request::Set { key, value } => {
+            engine.set_bytes(&key, &value).map(|()| Response::Ok)
+        }
+        Request::SetWithTtl { key, value, ttl } => engine
+            .set_bytes_with_ttl(&key, &value, ttl)
+            .map(|()| Response::Ok),
+        Request::Expire { key, ttl } => {
+            engine.expire_bytes(&key, ttl).map(|()| Response::Ok)
+        }
+        Request::Ttl { key } => engine.ttl_bytes(&key).map(Response::Ttl),
+        Request::Remove { key } => {
+            engine.remove_bytes(&key).map(|()| Response::Ok)
+        }
+        Request::Batch { batch } => {
+            engine.write_batch(batch).map(|()| Response::Ok)
+        }
+        Request::CompareAndSwap { key, expected, new } => engine
+            .compare_and_swap_bytes(&key, expected.as_deref(), new.as_deref())
+            .map(Response::Swapped),
+        Request::Incr { key, delta } => {
+            engine.incr_bytes(&key, delta).map(Response::Integer)
+        }
+        Request::Scan {
+            start,
+            end,
+            options,
+        } => engine
+            .scan_bytes((start, end), options)
+            .and_then(|pairs| pairs.collect())
+            .map(Response::Pairs),
+        Request::Keys { prefix } => {
+            engine.keys_bytes(&prefix).map(Response::Keys)
+        }
+        Request::Count => engine.len().map(Response::Count),
+        Request::ContainsKey { key } => {
+            engine.contains_key_bytes(&key).map(Response::Exists)
+        }
+    };
+    result.unwrap_or_else(|error| Response::Error {
+        code: error.code(),
+        message: error.to_string(),
+    })
+}
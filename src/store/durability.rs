@@ -0,0 +1,89 @@
+use super::{KvsError, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When writes to a `KvStore` are synced to disk.
+///
+/// Every write is handed to the operating system before it returns, so
+/// it survives the process crashing in any mode. What differs is how many
+/// acknowledged writes can be lost if the machine itself goes down.
+/// `SledKvsEngine::open_with_durability` maps these modes onto `sled`'s
+/// flushing.
+///
+/// Durability can also be parsed from a string, as done for the
+/// `--durability` flag of `kvs-server`: `always`, `never`, `<N>writes`,
+/// or `<N>ms`.
+///
+/// ```rust
+/// use kvs::Durability;
+/// use std::time::Duration;
+/// assert_eq!("always".parse::<Durability>().unwrap(), Durability::Always);
+/// assert_eq!(
+///     "100ms".parse::<Durability>().unwrap(),
+///     Durability::Interval(Duration::from_millis(100))
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Syncs every write before it returns. No acknowledged write is
+    /// ever lost.
+    Always,
+    /// Syncs after every `N` writes, so at most the last `N - 1`
+    /// acknowledged writes can be lost.
+    Writes(u32),
+    /// Syncs pending writes from a background thread at this interval,
+    /// so at most the writes acknowledged within the last interval can
+    /// be lost.
+    Interval(Duration),
+    /// Leaves syncing to the operating system. Any write that had not yet
+    /// been flushed by the operating system can be lost. This is the
+    /// default.
+    #[default]
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(durability: &str) -> Result<Self> {
+        let parse_count =
+            |count: &str| count.parse::<u32>().ok().filter(|&count| count > 0);
+        let parsed = match durability {
+            "always" => Some(Durability::Always),
+            "never" => Some(Durability::Never),
+            _ => {
+                if let Some(writes) = durability.strip_suffix("writes") {
+                    parse_count(writes).map(Durability::Writes)
+                } else if let Some(millis) = durability.strip_suffix("ms") {
+                    parse_count(millis).map(|millis| {
+                        Durability::Interval(Duration::from_millis(u64::from(
+                            millis,
+                        )))
+                    })
+                } else {
+                    None
+                }
+            }
+        };
+        parsed.ok_or_else(|| {
+            KvsError::InvalidInput(format!(
+                "Unknown durability: {} (expected always, never, <N>writes, or <N>ms)",
+                durability
+            ))
+        })
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::Always => write!(formatter, "always"),
+            Durability::Writes(writes) => write!(formatter, "{}writes", writes),
+            Durability::Interval(interval) => {
+                write!(formatter, "{}ms", interval.as_millis())
+            }
+            Durability::Never => write!(formatter, "never"),
+        }
+    }
+}
@@ -1,24 +1,36 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Entry {
-    Set(String, String),
-    Rm(String),
+    Set(Vec<u8>, Vec<u8>),
+    Rm(Vec<u8>),
+    /// A `Set` that expires at the given time, in milliseconds since the
+    /// Unix epoch.
+    SetWithExpiry(Vec<u8>, Vec<u8>, u64),
 }
 
 impl Entry {
-    pub fn rm(key: String) -> Self {
+    pub fn rm(key: Vec<u8>) -> Self {
         Entry::Rm(key)
     }
 
-    pub fn set(key: String, value: String) -> Self {
+    pub fn set(key: Vec<u8>, value: Vec<u8>) -> Self {
         Entry::Set(key, value)
     }
 
-    pub fn get_key(&self) -> &String {
+    pub fn set_with_expiry(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    ) -> Self {
+        Entry::SetWithExpiry(key, value, expires_at)
+    }
+
+    pub fn get_key(&self) -> &[u8] {
         match self {
             Entry::Set(key, ..) => key,
             Entry::Rm(key) => key,
+            Entry::SetWithExpiry(key, ..) => key,
         }
     }
 }
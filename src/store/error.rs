@@ -1,54 +1,211 @@
+#![allow(non_local_definitions)]
+use crate::{Engine, ErrorCode};
 use failure::Fail;
 use std::convert::From;
 use std::io;
+use std::num::ParseIntError;
+use std::path::PathBuf;
 use std::result;
+use std::string::FromUtf8Error;
 
 /// # KvsError
 /// This error is the user-facing error type for the KVS tool.
-#[derive(Clone, Debug, Fail)]
-#[fail(display = "{}", error_message)]
-pub struct KvsError {
-    /// The original error message as a string
-    pub error_message: String,
+///
+/// Each kind of failure has its own variant, so callers can tell them
+/// apart without inspecting messages. Errors raised by other libraries are
+/// kept as the cause of the variant wrapping them.
+#[derive(Debug, Fail)]
+#[non_exhaustive]
+pub enum KvsError {
+    /// The key does not exist in the store.
+    #[fail(display = "Key not found")]
+    KeyNotFound,
+    /// Reading or writing a file or socket failed.
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    /// A log entry could not be encoded or decoded as JSON.
+    #[fail(display = "{}", _0)]
+    Serialization(#[cause] serde_json::Error),
+    /// A network message could not be encoded or decoded.
+    #[fail(display = "{}", _0)]
+    Encoding(#[cause] bincode::Error),
+    /// A log segment holds an entry that cannot be read back.
+    #[fail(
+        display = "Corrupted log entry in {:?} at offset {}: {}",
+        file, offset, reason
+    )]
+    Corruption {
+        /// The segment holding the entry
+        file: PathBuf,
+        /// The offset of the entry within the segment
+        offset: u64,
+        /// What is wrong with the entry
+        reason: String,
+    },
+    /// A log segment was written in a format this version cannot read.
+    #[fail(display = "Unsupported log segment format version: {}", version)]
+    UnsupportedFormat {
+        /// The format version found in the segment header
+        version: u32,
+    },
+    /// Another writable handle, in this process or another, holds the
+    /// store open.
+    #[fail(display = "Store is locked by process {}: {:?}", owner, path)]
+    Locked {
+        /// The directory of the store
+        path: PathBuf,
+        /// The process id recorded by the handle holding the lock
+        owner: String,
+    },
+    /// The directory belongs to a different engine than the one requested.
+    #[fail(
+        display = "Wrong engine: {:?} belongs to engine `{}`, but engine `{}` was requested",
+        path, found, requested
+    )]
+    WrongEngine {
+        /// The directory being opened
+        path: PathBuf,
+        /// The engine that owns the directory
+        found: Engine,
+        /// The engine that was asked to open it
+        requested: Engine,
+    },
+    /// The store was opened with `error_if_exists` and already exists.
+    #[fail(display = "Store already exists: {:?}", _0)]
+    StoreExists(PathBuf),
+    /// The store does not exist and may not be created.
+    #[fail(display = "Store does not exist: {:?}", _0)]
+    StoreMissing(PathBuf),
+    /// A write was attempted on a store opened read-only.
+    #[fail(display = "Cannot write to a read-only store")]
+    ReadOnly,
+    /// The value being incremented is not an integer.
+    #[fail(display = "Value of key `{}` is not an integer: {}", key, value)]
+    NotAnInteger {
+        /// The key holding the value, decoded lossily
+        key: String,
+        /// The value found, decoded lossily
+        value: String,
+    },
+    /// Incrementing or decrementing a key overflowed an `i64`.
+    #[fail(display = "Integer overflow updating key `{}`", key)]
+    IntegerOverflow {
+        /// The key being updated, decoded lossily
+        key: String,
+    },
+    /// A key or value read through the string API is not valid UTF-8.
+    #[fail(display = "{}", _0)]
+    InvalidUtf8(#[cause] FromUtf8Error),
+    /// A number could not be parsed.
+    #[fail(display = "{}", _0)]
+    InvalidNumber(#[cause] ParseIntError),
+    /// An option, name, or command given by the user is not valid.
+    #[fail(display = "{}", _0)]
+    InvalidInput(String),
+    /// The `sled` engine failed.
+    #[fail(display = "{}", _0)]
+    Sled(#[cause] sled::Error),
+    /// A thread pool could not be built.
+    #[fail(display = "{}", _0)]
+    ThreadPool(#[cause] rayon::ThreadPoolBuildError),
+    /// The server sent a response that does not answer the request.
+    #[fail(display = "{}", _0)]
+    Protocol(String),
+    /// The server failed the request with an error that has no variant of
+    /// its own on the client side.
+    #[fail(display = "{}", message)]
+    Server {
+        /// The code the server reported
+        code: ErrorCode,
+        /// The server's description of the error
+        message: String,
+    },
 }
 
 impl From<io::Error> for KvsError {
     fn from(error: io::Error) -> Self {
-        KvsError {
-            error_message: error.to_string(),
-        }
+        KvsError::Io(error)
     }
 }
 
 impl From<serde_json::Error> for KvsError {
     fn from(error: serde_json::Error) -> Self {
-        KvsError {
-            error_message: error.to_string(),
-        }
+        KvsError::Serialization(error)
     }
 }
 
-impl From<std::num::ParseIntError> for KvsError {
-    fn from(error: std::num::ParseIntError) -> Self {
-        KvsError {
-            error_message: error.to_string(),
-        }
+impl From<bincode::Error> for KvsError {
+    fn from(error: bincode::Error) -> Self {
+        KvsError::Encoding(error)
+    }
+}
+
+impl From<ParseIntError> for KvsError {
+    fn from(error: ParseIntError) -> Self {
+        KvsError::InvalidNumber(error)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(error: sled::Error) -> Self {
+        KvsError::Sled(error)
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(error: FromUtf8Error) -> Self {
+        KvsError::InvalidUtf8(error)
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for KvsError {
+    fn from(error: rayon::ThreadPoolBuildError) -> Self {
+        KvsError::ThreadPool(error)
     }
 }
 
 impl KvsError {
-    /// Builds a `KvsError` from some string-like value.
+    /// Returns the code this error is reported with over the network.
     ///
     /// ## Usage
     /// ```
-    /// use kvs::KvsError;
-    /// let error = KvsError::from_string("Key not found.");
-    /// let other_error = KvsError::from_string(String::from("This also works"));
-    /// # assert_eq!(error.error_message, String::from("Key not found."));
+    /// use kvs::{ErrorCode, KvsError};
+    /// assert_eq!(KvsError::KeyNotFound.code(), ErrorCode::KeyNotFound);
     /// ```
-    pub fn from_string(error_message: impl Into<String>) -> Self {
-        KvsError {
-            error_message: error_message.into(),
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Io(..) => ErrorCode::Io,
+            KvsError::Serialization(..) => ErrorCode::Serialization,
+            KvsError::Corruption { .. }
+            | KvsError::UnsupportedFormat { .. } => ErrorCode::Corruption,
+            KvsError::Locked { .. } => ErrorCode::Locked,
+            KvsError::WrongEngine { .. } => ErrorCode::WrongEngine,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::NotAnInteger { .. } => ErrorCode::NotAnInteger,
+            KvsError::IntegerOverflow { .. } => ErrorCode::IntegerOverflow,
+            KvsError::InvalidUtf8(..) => ErrorCode::InvalidUtf8,
+            KvsError::InvalidNumber(..) | KvsError::InvalidInput(..) => {
+                ErrorCode::InvalidInput
+            }
+            KvsError::Sled(..) => ErrorCode::Storage,
+            KvsError::Encoding(..) | KvsError::Protocol(..) => {
+                ErrorCode::Protocol
+            }
+            KvsError::Server { code, .. } => *code,
+            KvsError::StoreExists(..)
+            | KvsError::StoreMissing(..)
+            | KvsError::ThreadPool(..) => ErrorCode::Other,
+        }
+    }
+
+    /// Rebuilds an error a server reported with `code` and `message`.
+    /// Codes whose variants carry no details come back as those variants.
+    pub(crate) fn from_server(code: ErrorCode, message: String) -> Self {
+        match code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            code => KvsError::Server { code, message },
         }
     }
 }
@@ -0,0 +1,397 @@
+//! # Format
+//! The on-disk layout of log segments.
+//!
+//! A segment starts with a header made of the magic bytes `KVS\0` followed
+//! by the format version as a little-endian `u32`. Every entry after it is
+//! written as a frame:
+//!
+//! | bytes | contents                                  |
+//! |-------|-------------------------------------------|
+//! | 4     | payload length, little-endian `u32`       |
+//! | 4     | CRC32 of the payload, little-endian `u32` |
+//! | n     | payload: the encoded entry                |
+//!
+//! In version 3, the current version, the payload is a one-byte op tag
+//! followed by the key and, for `Set`, the value, each as a little-endian
+//! `u32` length and that many bytes:
+//!
+//! | op tag | entry          | fields       |
+//! |--------|----------------|--------------|
+//! | 0      | `Set`          | key, value   |
+//! | 1      | `Rm`           | key          |
+//! | 2      | begin of batch |              |
+//! | 3      | commit batch   |              |
+//! | 4      | `Set` with TTL | key, value   |
+//!
+//! A `Set` with a TTL is followed by its expiry time, in milliseconds since
+//! the Unix epoch, as a little-endian `u64`.
+//!
+//! The entries of a write batch are written between a begin and a commit
+//! marker. They only take effect once the commit marker has been read.
+//!
+//! Version 3 introduced the `Set` with TTL entry, so that releases which
+//! do not know its op tag reject newer segments instead of mistaking them
+//! for corrupted ones. Version 2, the binary layout without it, was never
+//! released and is not read.
+//!
+//! Older segments are still read, but never written. In version 1 the
+//! payload is the entry serialized as JSON, and segments written before
+//! the header was introduced hold a bare stream of JSON entries.
+
+use super::{Entry, KvsError, Result};
+use serde::Deserialize;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+const MAGIC: [u8; 4] = *b"KVS\0";
+const VERSION: u32 = 3;
+const HEADER_LENGTH: usize = 8;
+const FRAME_HEADER_LENGTH: usize = 8;
+
+const SET_TAG: u8 = 0;
+const RM_TAG: u8 = 1;
+const BEGIN_BATCH_TAG: u8 = 2;
+const COMMIT_BATCH_TAG: u8 = 3;
+const SET_WITH_EXPIRY_TAG: u8 = 4;
+
+/// How the entries in a segment are laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentFormat {
+    /// A bare stream of JSON entries with no header or framing.
+    Legacy,
+    /// Version 1: checksummed frames holding JSON entries.
+    JsonFrames,
+    /// Version 3: checksummed frames holding binary entries.
+    BinaryFrames,
+}
+
+/// An entry as serialized in the JSON formats, where keys and values are
+/// always strings.
+#[derive(Deserialize)]
+pub enum JsonEntry {
+    Set(String, String),
+    Rm(String),
+}
+
+impl From<JsonEntry> for Entry {
+    fn from(entry: JsonEntry) -> Self {
+        match entry {
+            JsonEntry::Set(key, value) => {
+                Entry::set(key.into_bytes(), value.into_bytes())
+            }
+            JsonEntry::Rm(key) => Entry::rm(key.into_bytes()),
+        }
+    }
+}
+
+/// The outcome of reading a single frame while replaying a segment.
+pub enum Frame {
+    /// A complete frame whose checksum matched.
+    Entry(Entry),
+    /// The entries up to the next `CommitBatch` form a single write batch.
+    BeginBatch,
+    /// The write batch since the last `BeginBatch` is complete.
+    CommitBatch,
+    /// The segment ended cleanly on a frame boundary.
+    End,
+    /// The frame was cut short or failed its checksum.
+    Invalid,
+}
+
+/// Writes the header that every new segment starts with.
+pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Reads the header of a segment and leaves `reader` at its first entry.
+///
+/// Returns `None` if the segment is too short to hold a complete header,
+/// which is only expected of a segment whose creation was interrupted.
+pub fn read_header<R: Read + Seek>(
+    reader: &mut R,
+) -> Result<Option<SegmentFormat>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0; HEADER_LENGTH];
+    let header_length = read_up_to(reader, &mut header)?;
+    if header_length == 0 || header[0] != MAGIC[0] {
+        reader.seek(SeekFrom::Start(0))?;
+        return Ok(Some(SegmentFormat::Legacy));
+    }
+    if header_length < HEADER_LENGTH {
+        return Ok(None);
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..]);
+    match (header[..4] == MAGIC, u32::from_le_bytes(version)) {
+        (true, 1) => Ok(Some(SegmentFormat::JsonFrames)),
+        (true, VERSION) => Ok(Some(SegmentFormat::BinaryFrames)),
+        (_, version) => Err(KvsError::UnsupportedFormat { version }),
+    }
+}
+
+/// Writes `entry` as a single frame in the current format.
+pub fn write_entry<W: Write>(writer: &mut W, entry: &Entry) -> Result<()> {
+    write_frame(writer, &encode_entry(entry))
+}
+
+/// Writes the marker that starts a write batch.
+pub fn write_begin_batch<W: Write>(writer: &mut W) -> Result<()> {
+    write_frame(writer, &[BEGIN_BATCH_TAG])
+}
+
+/// Writes the marker that commits the write batch started last.
+pub fn write_commit_batch<W: Write>(writer: &mut W) -> Result<()> {
+    write_frame(writer, &[COMMIT_BATCH_TAG])
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    Ok(())
+}
+
+/// Reads the next frame while replaying a segment.
+pub fn read_frame<R: Read>(
+    format: SegmentFormat,
+    reader: &mut R,
+) -> Result<Frame> {
+    let mut payload = Vec::new();
+    match read_payload(reader, &mut payload)? {
+        Payload::Complete => {}
+        Payload::End => return Ok(Frame::End),
+        Payload::Invalid => return Ok(Frame::Invalid),
+    }
+    let entry = match (format, payload.as_slice()) {
+        (SegmentFormat::BinaryFrames, [BEGIN_BATCH_TAG]) => {
+            return Ok(Frame::BeginBatch)
+        }
+        (SegmentFormat::BinaryFrames, [COMMIT_BATCH_TAG]) => {
+            return Ok(Frame::CommitBatch)
+        }
+        (SegmentFormat::BinaryFrames, _) => decode_entry(&payload),
+        _ => serde_json::from_slice::<JsonEntry>(&payload)
+            .ok()
+            .map(Entry::from),
+    };
+    Ok(entry.map_or(Frame::Invalid, Frame::Entry))
+}
+
+/// Reads the entry that `reader` is positioned at. Any problem with the
+/// entry is reported as an error, since positions only ever point at
+/// entries that were replayed successfully.
+pub fn read_entry<R: Read>(
+    format: SegmentFormat,
+    reader: &mut R,
+) -> Result<Entry> {
+    match format {
+        SegmentFormat::Legacy => {
+            let entry: JsonEntry = serde_json::from_reader(reader)?;
+            Ok(entry.into())
+        }
+        _ => match read_frame(format, reader)? {
+            Frame::Entry(entry) => Ok(entry),
+            _ => Err(invalid_entry("Log entry failed its checksum.")),
+        },
+    }
+}
+
+/// Reads the value of the entry that `reader` is positioned at into
+/// `value`, returning `false`, with `value` left empty, if the entry is a
+/// removal. As with `read_entry`, any problem with the entry is an error.
+///
+/// Binary frames are read straight into `value`, so reading one does not
+/// allocate once `value` has grown large enough to hold it.
+pub fn read_value<R: Read>(
+    format: SegmentFormat,
+    reader: &mut R,
+    value: &mut Vec<u8>,
+) -> Result<bool> {
+    value.clear();
+    if format != SegmentFormat::BinaryFrames {
+        return match read_entry(format, reader)? {
+            Entry::Set(_, entry_value)
+            | Entry::SetWithExpiry(_, entry_value, _) => {
+                *value = entry_value;
+                Ok(true)
+            }
+            Entry::Rm(..) => Ok(false),
+        };
+    }
+
+    if read_payload(reader, value)? != Payload::Complete {
+        return Err(invalid_entry("Log entry failed its checksum."));
+    }
+    match locate_value(value) {
+        Some(Some(value_range)) => {
+            let length = value_range.len();
+            value.copy_within(value_range, 0);
+            value.truncate(length);
+            Ok(true)
+        }
+        Some(None) => {
+            value.clear();
+            Ok(false)
+        }
+        None => Err(invalid_entry("Malformed log entry.")),
+    }
+}
+
+/// The outcome of reading the payload of a frame.
+#[derive(Debug, PartialEq, Eq)]
+enum Payload {
+    /// The payload was read and its checksum matched.
+    Complete,
+    /// The segment ended cleanly before the frame.
+    End,
+    /// The frame was cut short or failed its checksum.
+    Invalid,
+}
+
+/// Reads the payload of the next frame into `payload`, which is cleared
+/// first.
+fn read_payload<R: Read>(
+    reader: &mut R,
+    payload: &mut Vec<u8>,
+) -> Result<Payload> {
+    payload.clear();
+    let mut frame_header = [0; FRAME_HEADER_LENGTH];
+    match read_up_to(reader, &mut frame_header)? {
+        0 => return Ok(Payload::End),
+        FRAME_HEADER_LENGTH => {}
+        _ => return Ok(Payload::Invalid),
+    }
+
+    let mut payload_length = [0; 4];
+    payload_length.copy_from_slice(&frame_header[..4]);
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&frame_header[4..]);
+
+    let payload_length = u32::from_le_bytes(payload_length) as u64;
+    reader.take(payload_length).read_to_end(payload)?;
+    if payload.len() as u64 != payload_length
+        || crc32fast::hash(payload) != u32::from_le_bytes(checksum)
+    {
+        return Ok(Payload::Invalid);
+    }
+    Ok(Payload::Complete)
+}
+
+fn encode_entry(entry: &Entry) -> Vec<u8> {
+    let mut payload = Vec::new();
+    match entry {
+        Entry::Set(key, value) => {
+            payload.push(SET_TAG);
+            encode_field(&mut payload, key);
+            encode_field(&mut payload, value);
+        }
+        Entry::Rm(key) => {
+            payload.push(RM_TAG);
+            encode_field(&mut payload, key);
+        }
+        Entry::SetWithExpiry(key, value, expires_at) => {
+            payload.push(SET_WITH_EXPIRY_TAG);
+            encode_field(&mut payload, key);
+            encode_field(&mut payload, value);
+            payload.extend_from_slice(&expires_at.to_le_bytes());
+        }
+    }
+    payload
+}
+
+fn encode_field(payload: &mut Vec<u8>, field: &[u8]) {
+    payload.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    payload.extend_from_slice(field);
+}
+
+/// Decodes a binary payload, returning `None` if it is malformed.
+fn decode_entry(payload: &[u8]) -> Option<Entry> {
+    let (&tag, mut fields) = payload.split_first()?;
+    let entry = match tag {
+        SET_TAG => {
+            let key = decode_field(&mut fields)?.to_vec();
+            Entry::Set(key, decode_field(&mut fields)?.to_vec())
+        }
+        RM_TAG => Entry::Rm(decode_field(&mut fields)?.to_vec()),
+        SET_WITH_EXPIRY_TAG => {
+            let key = decode_field(&mut fields)?.to_vec();
+            let value = decode_field(&mut fields)?.to_vec();
+            if fields.len() < 8 {
+                return None;
+            }
+            let (expires_at, rest) = fields.split_at(8);
+            let mut expires_at_bytes = [0; 8];
+            expires_at_bytes.copy_from_slice(expires_at);
+            fields = rest;
+            Entry::SetWithExpiry(
+                key,
+                value,
+                u64::from_le_bytes(expires_at_bytes),
+            )
+        }
+        _ => return None,
+    };
+    if fields.is_empty() {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// Finds where the value sits in a binary payload, returning `None` if the
+/// payload is malformed and `Some(None)` if it holds a removal.
+fn locate_value(payload: &[u8]) -> Option<Option<Range<usize>>> {
+    let (&tag, mut fields) = payload.split_first()?;
+    match tag {
+        SET_TAG | SET_WITH_EXPIRY_TAG => {
+            decode_field(&mut fields)?;
+            let start = payload.len() - fields.len() + 4;
+            let value = decode_field(&mut fields)?;
+            Some(Some(start..start + value.len()))
+        }
+        RM_TAG => Some(None),
+        _ => None,
+    }
+}
+
+fn decode_field<'a>(fields: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if fields.len() < 4 {
+        return None;
+    }
+    let (length, rest) = fields.split_at(4);
+    let mut length_bytes = [0; 4];
+    length_bytes.copy_from_slice(length);
+    let length = u32::from_le_bytes(length_bytes) as usize;
+    if rest.len() < length {
+        return None;
+    }
+    let (field, rest) = rest.split_at(length);
+    *fields = rest;
+    Some(field)
+}
+
+/// Fills as much of `buffer` as the reader allows, returning how many
+/// bytes were read. Unlike `read_exact`, running out of input early is
+/// not an error.
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reports an entry that was read in full but cannot be decoded.
+fn invalid_entry(reason: &str) -> KvsError {
+    io::Error::new(io::ErrorKind::InvalidData, reason).into()
+}
@@ -0,0 +1,165 @@
+//! # Hint Files
+//! Compaction writes a hint file (`<generation>.hint`) next to every
+//! segment it produces, listing where each key's entry lives in that
+//! segment. Opening a store loads the hint instead of replaying the
+//! segment, which avoids reading any values.
+//!
+//! A hint file starts with the magic bytes `KVH\0` and the format version,
+//! followed by the generation of its segment and the segment's length,
+//! each as little-endian integers. One record per key comes after that:
+//!
+//! | bytes | contents                                     |
+//! |-------|----------------------------------------------|
+//! | 4     | key length, little-endian `u32`              |
+//! | n     | key                                          |
+//! | 8     | offset of the entry, little-endian `u64`     |
+//! | 8     | length of the entry, little-endian `u64`     |
+//! | 8     | expiry in ms since the epoch, or 0 for none  |
+//!
+//! The file ends with a CRC32 of everything before it. A hint that is
+//! missing, fails its checksum, or does not match its segment is ignored,
+//! and the segment is replayed in full instead.
+
+use super::{Position, Result};
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 4] = *b"KVH\0";
+const VERSION: u32 = 2;
+const CHECKSUM_LENGTH: usize = 4;
+
+/// The position of every key in a segment, as listed by its hint file.
+type Hint = Vec<(Vec<u8>, Position)>;
+
+/// Returns the path of the hint file for the segment `generation`.
+pub fn get_hint_path(directory: &Path, generation: u64) -> PathBuf {
+    directory.join(format!("{}.hint", generation))
+}
+
+/// Writes the hint file for the segment `generation`, which is
+/// `segment_length` bytes long and holds the entries at `positions`.
+pub fn write_hint(
+    directory: &Path,
+    generation: u64,
+    segment_length: u64,
+    positions: &[(Vec<u8>, Position)],
+) -> Result<()> {
+    let mut contents = Vec::new();
+    contents.extend_from_slice(&MAGIC);
+    contents.extend_from_slice(&VERSION.to_le_bytes());
+    contents.extend_from_slice(&generation.to_le_bytes());
+    contents.extend_from_slice(&segment_length.to_le_bytes());
+    for (key, position) in positions {
+        contents.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        contents.extend_from_slice(key);
+        contents.extend_from_slice(&position.start_position.to_le_bytes());
+        contents.extend_from_slice(&position.length.to_le_bytes());
+        let expires_at = position.expires_at.unwrap_or(0);
+        contents.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    let checksum = crc32fast::hash(&contents);
+    contents.extend_from_slice(&checksum.to_le_bytes());
+
+    let mut file = File::create(get_hint_path(directory, generation))?;
+    file.write_all(&contents)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Reads the hint file for the segment `generation`, returning `None` if
+/// there is none or it cannot be trusted.
+pub fn read_hint(
+    directory: &Path,
+    generation: u64,
+    segment_length: u64,
+) -> Result<Option<Hint>> {
+    let contents = match fs::read(get_hint_path(directory, generation)) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok(None)
+        }
+        Err(error) => return Err(error.into()),
+    };
+    let positions = decode_hint(&contents, generation, segment_length);
+    if positions.is_none() {
+        warn!(
+            "Ignoring invalid hint file for segment {}, replaying it instead",
+            generation
+        );
+    }
+    Ok(positions)
+}
+
+/// Removes the hint file for the segment `generation`, if there is one.
+pub fn remove_hint(directory: &Path, generation: u64) -> Result<()> {
+    match fs::remove_file(get_hint_path(directory, generation)) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => {
+            Err(error.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn decode_hint(
+    contents: &[u8],
+    generation: u64,
+    segment_length: u64,
+) -> Option<Hint> {
+    if contents.len() < CHECKSUM_LENGTH {
+        return None;
+    }
+    let (mut records, checksum) =
+        contents.split_at(contents.len() - CHECKSUM_LENGTH);
+    if crc32fast::hash(records).to_le_bytes() != checksum {
+        return None;
+    }
+
+    if take(&mut records, 4)? != MAGIC
+        || take_u32(&mut records)? != VERSION
+        || take_u64(&mut records)? != generation
+        || take_u64(&mut records)? != segment_length
+    {
+        return None;
+    }
+    let mut positions = Vec::new();
+    while !records.is_empty() {
+        let key_length = take_u32(&mut records)? as usize;
+        let key = take(&mut records, key_length)?.to_vec();
+        let start_position = take_u64(&mut records)?;
+        let length = take_u64(&mut records)?;
+        let expires_at = Some(take_u64(&mut records)?).filter(|&at| at != 0);
+        positions.push((
+            key,
+            Position {
+                file_index: generation,
+                start_position,
+                length,
+                expires_at,
+            },
+        ));
+    }
+    Some(positions)
+}
+
+fn take<'a>(records: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if records.len() < length {
+        return None;
+    }
+    let (taken, rest) = records.split_at(length);
+    *records = rest;
+    Some(taken)
+}
+
+fn take_u32(records: &mut &[u8]) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(take(records, 4)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn take_u64(records: &mut &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(records, 8)?);
+    Some(u64::from_le_bytes(bytes))
+}
@@ -3,164 +3,308 @@
 //! `KVS` is a key-value store used in the `kvs` command-line utility.
 //!
 //! ## Serialization Format
-//! This crate uses the `serde_json` crate for (de-)serialization.
-//! For development purposes, it's helpful to have this in a standard, readable format.
-//! Eventually, however, it will likely be in the best interest of performance
-//! to change this for a different format.
+//! Entries are written in a compact binary encoding, each wrapped in a
+//! checksummed frame, as described in the `format` module. Segments carry
+//! a format version in their header, so stores written by earlier versions,
+//! which serialized entries with `serde_json`, can still be opened. Their
+//! entries are rewritten in the current format as they are compacted.
 //!
-//! TODO: Benchmark different serialization formats.
 //! TODO: Use benchmark tests to compare similar tools.
 //! TODO: Benchmarks should also be added to Github Action.
 //!
+//! ## Log Segments
+//! Entries are appended to numbered segment files (`<generation>.log`).
+//! The active segment keeps accepting entries until it grows past the
+//! configured segment size, at which point the next generation is started.
+//! Reopening a store carries on writing in the newest segment, unless it is
+//! already full or was written in an older format.
+//! A `Position` in the index records the generation, offset, and length
+//! of the entry it points to.
+//!
+//! ## Durability
+//! Every write is flushed to the operating system before it returns, but
+//! whether it is also synced to disk depends on the configured
+//! `Durability`: after every write, after every `N` writes, on an interval
+//! from a background thread, or never. Segments are always synced when
+//! writing moves past them, and pending writes are synced when the last
+//! handle to the store is dropped.
+//!
+//! ## Write Batches
+//! The entries of a `WriteBatch` are written back-to-back between a begin
+//! and a commit marker, and always into the same segment. Replay only
+//! applies them once it reaches the commit marker, so a batch torn by a
+//! crash is dropped as a whole, and the index is only updated once the
+//! whole batch has been written.
+//!
+//! ## Expiration
+//! A key set with a time-to-live is written with its expiry time, which the
+//! index keeps alongside the key's position. Reads check it against the
+//! clock, so an expired key disappears immediately, even though its entry
+//! stays in the log until the next compaction drops it.
+//!
+//! ## Locking
+//! A writable store holds an advisory lock on its data directory for as
+//! long as it is open, so a second writer, in this process or another,
+//! fails to open it instead of corrupting the log. Read-only stores do not
+//! take the lock and can be opened alongside a writer.
+//!
+//! ## Recovery
+//! Opening a store replays every segment. If the newest segment ends in a
+//! torn or corrupted entry, as left behind by a crash mid-write, the
+//! segment is truncated to the last intact entry. A read-only store leaves
+//! the segment as it is and skips the entry. Older segments are synced to
+//! disk before writing moves past them, so a bad entry in one of those is
+//! reported as an error instead.
+//!
+//! ## Compaction
+//! Overwriting or removing a key leaves its previous entry in the log as
+//! stale bytes. Once enough stale bytes accumulate, the active segment is
+//! closed and a background thread copies every live entry from the closed
+//! segments into a fresh one, while new writes carry on in a new active
+//! segment. The index is then pointed at the copies, skipping any key that
+//! was written again in the meantime, and only then are the closed
+//! segments deleted. The fresh segment is written under a temporary name
+//! and renamed into place once complete, so a crash part-way through never
+//! leaves a half-written segment in the log.
+//!
+//! Every compacted segment also gets a hint file listing the position of
+//! each key in it, as described in the `hint` module. Opening a store loads
+//! those hints instead of replaying the segments they describe.
+//!
 
 extern crate serde;
+use super::format::{self, Frame, JsonEntry, SegmentFormat};
+use super::hint;
+use super::lock::DirectoryLock;
 use super::{
-    BufReaderWithPosition, BufWriterWithPosition, Entry, KvsError, ParsePath,
-    Position, Result,
+    BufReaderWithPosition, BufWriterWithPosition, Durability, Entry,
+    KvStoreOptions, KvsError, ParsePath, Position, Result,
 };
-use crate::KvsEngine;
+use crate::engine::{add_delta, expiry};
+use crate::{prefix_range_bytes, KvsEngine, Scan, ScanOptions, WriteBatch};
+use crossbeam_skiplist::SkipMap;
+use crossbeam_utils::atomic::AtomicCell;
+use log::{error, warn};
 use serde_json::Deserializer;
-use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::collections::{btree_map, BTreeMap};
 use std::fs;
-use std::fs::{create_dir, File};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::fs::{create_dir, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Take, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-const COMPACTION_MINIMUM: u64 = 500;
+/// Maps every key to the position of its latest entry.
+///
+/// Positions are swapped in place rather than by inserting a new node,
+/// because replacing a node in a `SkipMap` removes the old one first, so a
+/// concurrent read could briefly find the key missing.
+type Index = SkipMap<Vec<u8>, AtomicCell<Position>>;
 
 /// This struct serves as the main interface for storing and retrieving
 /// data from the store. It uses a log-based file structure to store
 /// values on disk and a log-pointer cache to store the latest references
 /// to given keys.
-#[derive(Debug)]
+///
+/// `KvStore` is cheap to clone, and clones share the same underlying
+/// store. Each clone keeps its own file readers, so reads never contend
+/// with each other, while writes are serialized through a single writer.
+#[derive(Clone, Debug)]
 pub struct KvStore {
-    directory: PathBuf,
-    store: BTreeMap<String, Position>,
-    reader_map: HashMap<u64, BufReaderWithPosition<File>>,
-    writer: BufWriterWithPosition<File>,
-    next_command_position: u64,
-    compaction_counter: u64,
+    store: Arc<Index>,
+    reader: KvStoreReader,
+    /// `None` if the store was opened read-only.
+    writer: Option<Arc<WriterHandle>>,
+    is_compacting: Arc<AtomicBool>,
 }
 
 impl KvStore {
-    /// Initializes `KvStore` readers and writers.
-    pub fn open(path: impl Into<PathBuf> + Clone) -> Result<KvStore> {
+    /// Initializes `KvStore` readers and writers using the default
+    /// `KvStoreOptions`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStoreOptions::new().open(path)
+    }
+
+    /// Opens the store under `path` for reading only, as described in
+    /// `KvStoreOptions::read_only`.
+    ///
+    /// No files are ever created, modified, or removed, and `set` and
+    /// `remove` fail. The store can be opened while another process holds
+    /// the writer lock, but it sees the log as it was when it was opened,
+    /// and reads of keys whose segments have since been compacted away by
+    /// the writer fail.
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set(String::from("river"), String::from("Douro")).unwrap();
+    ///
+    /// let read_only = KvStore::open_read_only(temp_dir.path()).unwrap();
+    /// let river = read_only.get(String::from("river")).unwrap();
+    /// assert_eq!(river, Some(String::from("Douro")));
+    /// assert!(read_only.remove(String::from("river")).is_err());
+    /// ```
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStoreOptions::new().read_only(true).open(path)
+    }
+
+    pub(super) fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
         let mut path_buf: PathBuf = path.into();
-        path_buf.push(".kvs");
-        if !path_buf.exists() {
+        path_buf.push(&options.directory_name);
+        if path_buf.exists() {
+            if options.error_if_exists {
+                return Err(KvsError::StoreExists(path_buf));
+            }
+        } else if options.create_if_missing && !options.read_only {
             create_dir(path_buf.clone()).map_err(KvsError::from)?;
+        } else {
+            return Err(KvsError::StoreMissing(path_buf));
         }
 
-        let mut store = BTreeMap::new();
-        let mut reader_map = HashMap::new();
+        let directory = Arc::new(path_buf);
+        let lock = if options.read_only {
+            None
+        } else {
+            let lock = DirectoryLock::acquire(&directory)?;
+            remove_unfinished_compactions(&directory)?;
+            Some(lock)
+        };
 
-        get_descending_files_in_directory(path_buf.clone())
-            .iter()
-            .for_each(|path| {
-                let mut buffer = BufReaderWithPosition::new(
-                    File::open(path.clone()).unwrap(),
-                )
-                .unwrap();
-                load_entry(path.clone(), &mut store, &mut buffer).unwrap();
-                reader_map
-                    .insert(path.parse_number_from_path().unwrap(), buffer);
-            });
+        let store = Arc::new(SkipMap::new());
+        let generations = get_sorted_generations(&directory)?;
+        let mut uncompacted = 0;
+        for &generation in &generations {
+            let is_newest = Some(&generation) == generations.last();
+            if !is_newest {
+                let segment_length =
+                    fs::metadata(get_path_for_index(&directory, generation))?
+                        .len();
+                let hint =
+                    hint::read_hint(&directory, generation, segment_length)?;
+                if let Some(positions) = hint {
+                    for (key, position) in positions {
+                        uncompacted += index_position(key, position, &store);
+                    }
+                    continue;
+                }
+            }
+            uncompacted += load_entry(
+                &directory,
+                generation,
+                &store,
+                is_newest,
+                !options.read_only,
+            )?;
+        }
 
-        let next_command_position =
-            reader_map.keys().max().map(|num| num + 1).unwrap_or(0);
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let is_compacting = Arc::new(AtomicBool::new(false));
+        let reader =
+            KvStoreReader::new(Arc::clone(&directory), Arc::clone(&safe_point));
+        let lock = match lock {
+            Some(lock) => lock,
+            None => {
+                return Ok(KvStore {
+                    store,
+                    reader,
+                    writer: None,
+                    is_compacting,
+                })
+            }
+        };
+
+        let reopened = match generations.last() {
+            Some(&newest) => {
+                reopen_segment(&directory, newest, options.segment_size)?
+                    .map(|writer| (newest, writer))
+            }
+            None => None,
+        };
+        let (current_generation, segment) = match reopened {
+            Some(reopened) => reopened,
+            None => {
+                let generation = generations.last().map_or(0, |last| last + 1);
+                (generation, create_segment(&directory, generation)?)
+            }
+        };
+        let writer = KvStoreWriter {
+            writer: segment,
+            reader: reader.clone(),
+            directory,
+            store: Arc::clone(&store),
+            safe_point,
+            current_generation,
+            segment_size: options.segment_size,
+            uncompacted,
+            compaction_threshold: options.compaction_threshold,
+            is_compacting: Arc::clone(&is_compacting),
+            compaction: None,
+            durability: options.durability,
+            unsynced_writes: 0,
+        };
+        let writer = WriterHandle {
+            writer: Arc::new(Mutex::new(writer)),
+            _lock: lock,
+        };
+        if let Durability::Interval(interval) = options.durability {
+            spawn_syncer(writer.downgrade(), interval)?;
+        }
 
         Ok(KvStore {
-            directory: path_buf.clone(),
-            reader_map,
             store,
-            writer: BufWriterWithPosition::<File>::create(
-                path_buf,
-                next_command_position,
-            )?,
-            next_command_position,
-            compaction_counter: 0,
+            reader,
+            writer: Some(Arc::new(writer)),
+            is_compacting,
         })
     }
 
-    fn read_index(&mut self, index: Position) -> Result<Entry> {
-        let reader_option = self.reader_map.get_mut(&index.file_index);
-        if let Some(buffer) = reader_option {
-            buffer.seek(SeekFrom::Start(index.start_position))?;
-            buffer.take(index.length);
-            serde_json::from_reader(buffer).map_err(KvsError::from)
-        } else if !self.get_path_for_index(index.file_index).exists() {
-            Err(KvsError::from_string("No file exists at the given index."))
-        } else {
-            self.reader_map.insert(
-                index.file_index,
-                BufReaderWithPosition::new(File::open(
-                    self.get_path_for_index(index.file_index),
-                )?)?,
-            );
-            self.read_index(index)
-        }
-    }
-
-    fn compact_log(&mut self) -> Result<()> {
-        let mut directory_files: Vec<PathBuf> =
-            get_descending_files_in_directory(self.directory.clone());
-        directory_files.reverse();
-
-        let mut final_keys = HashMap::new();
-        directory_files.iter().for_each(|path| {
-            let key: Entry =
-                serde_json::from_reader(File::open(path).unwrap()).unwrap();
-            if final_keys.get(key.get_key()).is_some() {
-                fs::remove_file(path).expect("Could not delete file.");
-                self.reader_map
-                    .remove(&path.parse_number_from_path().unwrap());
-            } else {
-                final_keys.insert(key.get_key().clone(), true);
-            }
-        });
-        self.compaction_counter = 0;
-
-        Ok(())
+    /// Starts compacting the log on a background thread, regardless of
+    /// how many stale bytes have accumulated. Reads and writes continue
+    /// while the compaction runs.
+    ///
+    /// Does nothing if a compaction is already running, and fails if the
+    /// store is read-only.
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set(String::from("planet"), String::from("Mars")).unwrap();
+    /// store.compact().unwrap();
+    /// ```
+    pub fn compact(&self) -> Result<()> {
+        let handle = self.writer_handle()?;
+        handle.lock().start_compaction(handle.downgrade())
     }
 
-    fn get_path_for_index(&self, index: u64) -> PathBuf {
-        let mut directory = self.directory.clone();
-        directory.push(format!("{}.log", index));
-        directory
+    /// Returns whether a compaction is currently running in the background.
+    pub fn is_compacting(&self) -> bool {
+        self.is_compacting.load(Ordering::SeqCst)
     }
 
-    fn append_entry(&mut self, new_entry: Entry) -> Result<()> {
-        self.writer = BufWriterWithPosition::<File>::create(
-            self.directory.clone(),
-            self.next_command_position,
-        )?;
-        let start_position = self.writer.position;
-        serde_json::to_writer(&mut self.writer, &new_entry)?;
-        self.writer.flush()?;
-        match new_entry {
-            Entry::Set(key, ..) => {
-                self.store.insert(
-                    key,
-                    (
-                        self.next_command_position,
-                        start_position,
-                        self.writer.position,
-                    )
-                        .into(),
-                );
-            }
-            Entry::Rm(key) => {
-                self.store.remove(&key);
-            }
-        }
-        self.next_command_position += 1;
-        self.compaction_counter += 1;
-
-        if self.compaction_counter > COMPACTION_MINIMUM {
-            self.compact_log()?;
+    fn write<F, T>(&self, write: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<T>,
+    {
+        let handle = self.writer_handle()?;
+        let mut writer = handle.lock();
+        let result = write(&mut writer)?;
+        if writer.uncompacted > writer.compaction_threshold {
+            writer.start_compaction(handle.downgrade())?;
         }
+        Ok(result)
+    }
 
-        Ok(())
+    fn writer_handle(&self) -> Result<&WriterHandle> {
+        self.writer.as_deref().ok_or(KvsError::ReadOnly)
     }
 }
 
@@ -168,97 +312,1022 @@ impl KvsEngine for KvStore {
     /// Sets a new value for the given key in the store.
     /// ```rust
     /// use kvs::{KvStore, KvsEngine};
-    /// use std::env::temp_dir;
-    /// let mut store = KvStore::open(temp_dir()).unwrap();
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
     /// store.set(String::from("module_name"), String::from("kvs"));
     /// ```
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let new_entry = Entry::set(key, value);
-        self.append_entry(new_entry)
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write(|writer| writer.set(key, value))
     }
 
     /// Retrieves a value from the store.
     /// ```rust
     /// use kvs::{KvStore, KvsEngine};
-    /// use std::env::temp_dir;
-    /// let mut store = KvStore::open(temp_dir()).unwrap();
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
     /// store.set(String::from("name"), String::from("Caroline"));
     ///
     /// let name = store.get(String::from("name")).expect("Name was not found in store.").unwrap();
     /// assert_eq!(name, String::from("Caroline"));
+    ///
+    /// let mut buffer = Vec::new();
+    /// assert!(store.get_into(b"name", &mut buffer).unwrap());
+    /// assert_eq!(buffer, b"Caroline");
     /// ```
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let index = self.store.get(key.as_str());
-        if let Some(position) = index {
-            // This clone is to get around a mutable borrow reservation conflict.
-            // For more info, see the tracking issue: https://github.com/rust-lang/rust/issues/59159
-            let cloned_position = *position;
-            match self.read_index(cloned_position) {
-                Ok(Entry::Rm(..)) => Ok(None),
-                Ok(Entry::Set(.., value)) => Ok(Some(value.parse().unwrap())),
-                Err(error) => Err(error),
+    fn get_into(&self, key: &[u8], value: &mut Vec<u8>) -> Result<bool> {
+        loop {
+            let index = match live_position(&self.store, key) {
+                Some(index) => index,
+                None => {
+                    value.clear();
+                    return Ok(false);
+                }
+            };
+            match self.reader.read_value(index, value) {
+                // The segment was removed after the index was read, so the
+                // key has since been written elsewhere. Look it up again.
+                Err(..) if self.reader.is_removed(index.file_index) => {}
+                result => return result,
             }
-        } else {
-            Ok(None)
         }
     }
 
+    /// Sets a value that expires once the given time-to-live has passed.
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use std::time::Duration;
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// let session = String::from("session");
+    /// let ttl = Duration::from_secs(60);
+    /// store.set_with_ttl(session.clone(), String::from("token"), ttl).unwrap();
+    ///
+    /// let left = store.ttl(session.clone()).unwrap().unwrap();
+    /// assert!(left <= ttl);
+    /// store.set(session.clone(), String::from("token")).unwrap();
+    /// assert_eq!(store.ttl(session).unwrap(), None);
+    /// ```
+    fn set_bytes_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        self.write(|writer| writer.set_with_expiry(key, value, expires_at))
+    }
+
+    fn expire_bytes(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        self.write(|writer| writer.expire(key, expires_at))
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        live_position(&self.store, key)
+            .map(|position| position.expires_at.map(expiry::time_left))
+            .ok_or(KvsError::KeyNotFound)
+    }
+
     /// Removes the given key from the store.
     /// ```rust
     /// use kvs::{KvStore, KvsEngine};
-    /// use std::env::temp_dir;
-    /// let mut store = KvStore::open(temp_dir()).unwrap();
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
     /// store.set(String::from("album_name"), String::from("Blood Type"));
     /// store.remove(String::from("album_name"));
     /// assert!(store.get(String::from("album_name")).unwrap().is_none());
     /// ```
-    fn remove(&mut self, key: String) -> Result<()> {
-        let is_existing_value = self.get(key.clone())?.is_some();
-        if !is_existing_value {
-            Err(KvsError::from_string("Key not found"))
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write(|writer| writer.remove(key))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(|writer| writer.write_batch(batch))
+    }
+
+    /// Sets or removes a key only if its current value is `expected`,
+    /// returning whether it did. The check and the write happen under the
+    /// writer lock, so no other write can come between them.
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// let leader = String::from("leader");
+    ///
+    /// assert!(store.set_if_absent(leader.clone(), String::from("a")).unwrap());
+    /// assert!(!store.set_if_absent(leader.clone(), String::from("b")).unwrap());
+    /// assert!(store
+    ///     .compare_and_swap(
+    ///         leader.clone(),
+    ///         Some(String::from("a")),
+    ///         Some(String::from("b")),
+    ///     )
+    ///     .unwrap());
+    /// assert_eq!(store.get(leader).unwrap(), Some(String::from("b")));
+    /// ```
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.write(|writer| writer.compare_and_swap(key, expected, new))
+    }
+
+    /// Adds `delta` to the integer stored at a key, under the writer lock.
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    ///
+    /// assert_eq!(store.incr(String::from("visits"), 1).unwrap(), 1);
+    /// assert_eq!(store.incr(String::from("visits"), 10).unwrap(), 11);
+    /// assert_eq!(store.decr(String::from("visits"), 2).unwrap(), 9);
+    /// ```
+    fn incr_bytes(&self, key: &[u8], delta: i64) -> Result<i64> {
+        self.write(|writer| writer.incr(key, delta))
+    }
+
+    /// Iterates over the pairs whose keys fall within a range.
+    ///
+    /// The matching keys are taken from the index when the scan starts,
+    /// and their values are read as the iterator advances. Keys removed
+    /// in the meantime are skipped.
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine, ScanOptions};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set(String::from("a"), String::from("1")).unwrap();
+    /// store.set(String::from("b"), String::from("2")).unwrap();
+    /// store.set(String::from("c"), String::from("3")).unwrap();
+    ///
+    /// let pairs = store
+    ///     .scan(String::from("b").., ScanOptions::new())
+    ///     .unwrap()
+    ///     .collect::<kvs::Result<Vec<_>>>()
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     pairs,
+    ///     vec![
+    ///         (String::from("b"), String::from("2")),
+    ///         (String::from("c"), String::from("3")),
+    ///     ]
+    /// );
+    /// ```
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan<Vec<u8>>> {
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let now = expiry::now();
+        let keys = self
+            .store
+            .range(range)
+            .filter(|entry| !entry.value().load().is_expired(now))
+            .map(|entry| entry.key().clone());
+        let keys: Vec<Vec<u8>> = if options.reverse {
+            keys.rev().take(limit).collect()
         } else {
-            let entry = Entry::rm(key);
+            keys.take(limit).collect()
+        };
+
+        let store = self.clone();
+        Ok(Box::new(keys.into_iter().filter_map(move |key| {
+            match store.get_bytes(&key) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(error) => Some(Err(error)),
+            }
+        })))
+    }
+
+    /// Lists the keys starting with a prefix, answered from the index
+    /// alone.
+    /// ```rust
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set(String::from("user:1"), String::from("Ana")).unwrap();
+    /// store.set(String::from("user:2"), String::from("Rui")).unwrap();
+    /// store.set(String::from("team:1"), String::from("Ops")).unwrap();
+    ///
+    /// assert_eq!(store.keys("user:").unwrap(), vec!["user:1", "user:2"]);
+    /// assert_eq!(store.len().unwrap(), 3);
+    /// assert!(store.contains_key("team:1").unwrap());
+    /// ```
+    fn keys_bytes(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let now = expiry::now();
+        Ok(self
+            .store
+            .range(prefix_range_bytes(prefix))
+            .filter(|entry| !entry.value().load().is_expired(now))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    fn len(&self) -> Result<usize> {
+        let now = expiry::now();
+        Ok(self
+            .store
+            .iter()
+            .filter(|entry| !entry.value().load().is_expired(now))
+            .count())
+    }
+
+    fn contains_key_bytes(&self, key: &[u8]) -> Result<bool> {
+        Ok(live_position(&self.store, key).is_some())
+    }
+}
+
+/// Reads entries from the log on behalf of a single `KvStore` handle.
+///
+/// Every clone opens its own file handles, which is what allows reads
+/// to proceed on several threads at once without locking.
+#[derive(Debug)]
+struct KvStoreReader {
+    directory: Arc<PathBuf>,
+    /// Every generation below this one has been removed from disk.
+    safe_point: Arc<AtomicU64>,
+    reader_map:
+        RefCell<BTreeMap<u64, (SegmentFormat, BufReaderWithPosition<File>)>>,
+}
+
+impl KvStoreReader {
+    fn new(directory: Arc<PathBuf>, safe_point: Arc<AtomicU64>) -> Self {
+        KvStoreReader {
+            directory,
+            safe_point,
+            reader_map: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn is_removed(&self, generation: u64) -> bool {
+        generation < self.safe_point.load(Ordering::SeqCst)
+    }
+
+    /// Drops the handles of segments that have been removed, so that a
+    /// long-lived handle does not keep deleted files open.
+    ///
+    /// This runs on every read, so the map is only rebuilt once a segment
+    /// this handle has open was actually removed.
+    fn close_stale_readers(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let mut reader_map = self.reader_map.borrow_mut();
+        let has_stale_readers = reader_map
+            .keys()
+            .next()
+            .is_some_and(|&generation| generation < safe_point);
+        if has_stale_readers {
+            *reader_map = reader_map.split_off(&safe_point);
+        }
+    }
+
+    fn read_index(&self, index: Position) -> Result<Entry> {
+        self.read_at(index, |segment_format, reader| {
+            format::read_entry(segment_format, reader)
+        })
+    }
+
+    /// Reads the value of the entry at `index` into `value`, returning
+    /// `false` if the entry is a removal.
+    fn read_value(&self, index: Position, value: &mut Vec<u8>) -> Result<bool> {
+        self.read_at(index, |segment_format, reader| {
+            format::read_value(segment_format, reader, value)
+        })
+    }
+
+    /// Runs `read` on the entry at `index`, opening its segment if this
+    /// handle has not yet.
+    fn read_at<T>(
+        &self,
+        index: Position,
+        read: impl FnOnce(
+            SegmentFormat,
+            &mut Take<&mut BufReaderWithPosition<File>>,
+        ) -> Result<T>,
+    ) -> Result<T> {
+        self.close_stale_readers();
+        let mut reader_map = self.reader_map.borrow_mut();
+        let corruption = |reason: String| KvsError::Corruption {
+            file: get_path_for_index(&self.directory, index.file_index),
+            offset: index.start_position,
+            reason,
+        };
+        let (segment_format, buffer) = match reader_map.entry(index.file_index)
+        {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                let path =
+                    get_path_for_index(&self.directory, index.file_index);
+                if !path.exists() {
+                    return Err(corruption(
+                        "Log segment does not exist.".into(),
+                    ));
+                }
+                let mut buffer =
+                    BufReaderWithPosition::new(File::open(&path)?)?;
+                let segment_format = format::read_header(&mut buffer)?
+                    .ok_or_else(|| {
+                        corruption("Log segment has no header.".into())
+                    })?;
+                entry.insert((segment_format, buffer))
+            }
+        };
+        buffer.seek(SeekFrom::Start(index.start_position))?;
+        read(*segment_format, &mut buffer.take(index.length))
+            .map_err(|error| corruption(error.to_string()))
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        KvStoreReader::new(
+            Arc::clone(&self.directory),
+            Arc::clone(&self.safe_point),
+        )
+    }
+}
+
+/// The writer as seen by `KvStore` handles.
+///
+/// Unlike the writer itself, this is never shared with background threads,
+/// so it is dropped exactly when the last handle to the store is.
+#[derive(Debug)]
+struct WriterHandle {
+    writer: Arc<Mutex<KvStoreWriter>>,
+    /// Released only after the compaction has been joined on drop.
+    _lock: DirectoryLock,
+}
+
+impl WriterHandle {
+    fn lock(&self) -> MutexGuard<'_, KvStoreWriter> {
+        self.writer.lock().unwrap()
+    }
+
+    fn downgrade(&self) -> Weak<Mutex<KvStoreWriter>> {
+        Arc::downgrade(&self.writer)
+    }
+}
+
+impl Drop for WriterHandle {
+    /// Syncs pending writes and waits for a running compaction, so that a
+    /// closed store leaves its directory settled for whoever opens it next.
+    fn drop(&mut self) {
+        let compaction = {
+            let mut writer = self.lock();
+            if writer.durability != Durability::Never {
+                if let Err(error) = writer.sync() {
+                    error!("Failed to sync the log on close: {}", error);
+                }
+            }
+            writer.compaction.take()
+        };
+        if let Some(compaction) = compaction {
+            let _ = compaction.join();
+        }
+    }
+}
+
+/// Appends entries to the log. There is exactly one writer per store,
+/// shared behind a mutex, so appends are always serialized.
+#[derive(Debug)]
+struct KvStoreWriter {
+    directory: Arc<PathBuf>,
+    store: Arc<Index>,
+    safe_point: Arc<AtomicU64>,
+    reader: KvStoreReader,
+    writer: BufWriterWithPosition<File>,
+    current_generation: u64,
+    segment_size: u64,
+    /// The number of stale bytes in the log that compaction would free.
+    uncompacted: u64,
+    compaction_threshold: u64,
+    is_compacting: Arc<AtomicBool>,
+    compaction: Option<JoinHandle<()>>,
+    durability: Durability,
+    /// The number of writes flushed since the active segment last synced.
+    unsynced_writes: u32,
+}
+
+impl KvStoreWriter {
+    /// Reads the current value of `key`. Holding the writer means no
+    /// compaction can swap the index or remove segments underneath.
+    fn get(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let index = match live_position(&self.store, key) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let value = entry_value(self.reader.read_index(index)?);
+        Ok(value.map(|value| (value, index.expires_at)))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let current = self.get(key)?;
+        if current.as_ref().map(|(value, _)| value.as_slice()) != expected {
+            return Ok(false);
+        }
+        match (new, expected) {
+            (Some(value), _) => self.set(key, value)?,
+            (None, Some(..)) => self.remove(key)?,
+            (None, None) => {}
+        }
+        Ok(true)
+    }
+
+    fn incr(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let (current, expires_at) = self.get(key)?.unzip();
+        let value = add_delta(key, current.as_deref(), delta)?;
+        let new_value = value.to_string();
+        match expires_at.flatten() {
+            Some(expires_at) => {
+                self.set_with_expiry(key, new_value.as_bytes(), expires_at)?
+            }
+            None => self.set(key, new_value.as_bytes())?,
+        }
+        Ok(value)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let new_entry = Entry::set(key.to_vec(), value.to_vec());
+        self.append_entry(new_entry)
+    }
+
+    fn set_with_expiry(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> Result<()> {
+        let new_entry =
+            Entry::set_with_expiry(key.to_vec(), value.to_vec(), expires_at);
+        self.append_entry(new_entry)
+    }
+
+    /// Rewrites the value of `key` with a new expiry time.
+    fn expire(&mut self, key: &[u8], expires_at: u64) -> Result<()> {
+        match self.get(key)? {
+            Some((value, _)) => self.set_with_expiry(key, &value, expires_at),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        if live_position(&self.store, key).is_none() {
+            Err(KvsError::KeyNotFound)
+        } else {
+            let entry = Entry::rm(key.to_vec());
             self.append_entry(entry)
         }
     }
+
+    /// Closes the active segment and hands every segment up to it to a
+    /// background `Compaction`.
+    ///
+    /// The compacted segment takes the generation after the closed one,
+    /// and writing continues in the generation after that, so replaying
+    /// the log in order still applies newer entries last.
+    fn start_compaction(
+        &mut self,
+        writer: Weak<Mutex<KvStoreWriter>>,
+    ) -> Result<()> {
+        if self.is_compacting.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(finished) = self.compaction.take() {
+            finished.join().expect("The compaction thread panicked.");
+        }
+
+        let compaction = Compaction {
+            directory: Arc::clone(&self.directory),
+            reader: self.reader.clone(),
+            generation: self.current_generation + 1,
+            entries: self
+                .store
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().load()))
+                .collect(),
+        };
+        self.current_generation += 1;
+        self.start_segment()?;
+        self.uncompacted = 0;
+
+        self.is_compacting.store(true, Ordering::SeqCst);
+        let is_compacting = Arc::clone(&self.is_compacting);
+        let handle = thread::Builder::new()
+            .name(String::from("kvs-compaction"))
+            .spawn(move || {
+                if let Err(error) = compaction.run(&writer) {
+                    error!("Compaction failed: {}", error);
+                }
+                is_compacting.store(false, Ordering::SeqCst);
+            });
+        match handle {
+            Ok(handle) => {
+                self.compaction = Some(handle);
+                Ok(())
+            }
+            Err(error) => {
+                self.is_compacting.store(false, Ordering::SeqCst);
+                Err(KvsError::from(error))
+            }
+        }
+    }
+
+    /// Closes the active segment and starts writing to the next generation.
+    ///
+    /// The closed segment is synced first, so that only the newest segment
+    /// can ever be left with a torn entry.
+    fn start_segment(&mut self) -> Result<()> {
+        self.writer.sync()?;
+        self.unsynced_writes = 0;
+        self.current_generation += 1;
+        self.writer = create_segment(&self.directory, self.current_generation)?;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let batch_start = self.writer.position;
+        // A begin marker left behind without its commit marker would pull
+        // every later write into the batch when the log is replayed.
+        let positions = self.write_or_rewind(|writer| {
+            format::write_begin_batch(&mut writer.writer)?;
+            let mut positions = Vec::with_capacity(batch.len());
+            for entry in batch {
+                let position = writer.write_entry(&entry)?;
+                positions.push((entry, position));
+            }
+            format::write_commit_batch(&mut writer.writer)?;
+            writer.commit()?;
+            Ok(positions)
+        })?;
+
+        // The markers are stale as soon as they are written, since
+        // compaction copies the entries without them.
+        let entry_bytes: u64 =
+            positions.iter().map(|(_, position)| position.length).sum();
+        self.uncompacted += self.writer.position - batch_start - entry_bytes;
+        for (entry, position) in positions {
+            self.uncompacted += apply_entry(entry, position, &self.store);
+        }
+        self.finish_write()
+    }
+
+    fn append_entry(&mut self, new_entry: Entry) -> Result<()> {
+        let position = self.write_or_rewind(|writer| {
+            let position = writer.write_entry(&new_entry)?;
+            writer.commit()?;
+            Ok(position)
+        })?;
+        self.uncompacted += apply_entry(new_entry, position, &self.store);
+        self.finish_write()
+    }
+
+    /// Runs `write`, and if it fails, truncates the active segment back to
+    /// where it started. Otherwise whatever part of the failed write was
+    /// left in the buffer would reach the log with the next write, and the
+    /// write reported as failed would come back once the store is reopened.
+    fn write_or_rewind<T>(
+        &mut self,
+        write: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let start_position = self.writer.position;
+        let result = write(self);
+        if result.is_err() {
+            if let Err(error) = self.writer.rewind(start_position) {
+                error!("Failed to discard a failed write: {}", error);
+            }
+        }
+        result
+    }
+
+    /// Writes `entry` to the active segment, returning its position.
+    fn write_entry(&mut self, entry: &Entry) -> Result<Position> {
+        let start_position = self.writer.position;
+        format::write_entry(&mut self.writer, entry)?;
+        Ok(Position::from((
+            self.current_generation,
+            start_position,
+            self.writer.position,
+        )))
+    }
+
+    /// Hands what was written to the operating system, and syncs it if
+    /// the durability setting calls for it.
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.unsynced_writes += 1;
+        match self.durability {
+            Durability::Always => self.sync(),
+            Durability::Writes(writes) if self.unsynced_writes >= writes => {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts a new segment once the active one is full.
+    fn finish_write(&mut self) -> Result<()> {
+        if self.writer.position >= self.segment_size {
+            self.start_segment()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the active segment if anything was written since it last was.
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced_writes > 0 {
+            self.writer.sync()?;
+            self.unsynced_writes = 0;
+        }
+        Ok(())
+    }
 }
 
-fn load_entry(
-    entry_path: PathBuf,
-    store: &mut BTreeMap<String, Position>,
-    reader: &mut BufReaderWithPosition<File>,
+/// Starts the background thread that syncs the log for
+/// `Durability::Interval`. It stops once the store has been closed.
+fn spawn_syncer(
+    writer: Weak<Mutex<KvStoreWriter>>,
+    interval: Duration,
 ) -> Result<()> {
-    let mut start_position = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Entry>();
-    while let Some(entry) = stream.next() {
-        let end_position = stream.byte_offset();
-        match entry.expect("Entry could not be deserialized.") {
-            Entry::Set(key, ..) => {
-                store.insert(
-                    key,
-                    (
-                        entry_path.parse_number_from_path()?,
-                        start_position,
-                        end_position as u64,
-                    )
-                        .into(),
-                );
+    thread::Builder::new()
+        .name(String::from("kvs-sync"))
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let writer = match writer.upgrade() {
+                Some(writer) => writer,
+                None => return,
+            };
+            let result = writer.lock().unwrap().sync();
+            if let Err(error) = result {
+                error!("Failed to sync the log: {}", error);
             }
-            Entry::Rm(key) => {
-                store.remove(&key);
+        })?;
+    Ok(())
+}
+
+/// A snapshot of the index taken when the active segment was closed,
+/// to be rewritten into a fresh segment by a background thread.
+struct Compaction {
+    directory: Arc<PathBuf>,
+    reader: KvStoreReader,
+    generation: u64,
+    entries: Vec<(Vec<u8>, Position)>,
+}
+
+impl Compaction {
+    fn run(self, writer: &Weak<Mutex<KvStoreWriter>>) -> Result<()> {
+        let compacted_positions = self.copy_entries()?;
+
+        // If the store has been closed, the copies are left in place next
+        // to the originals. Both hold the same values, and the next
+        // compaction will clean them up.
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        {
+            let writer = writer.lock().unwrap();
+            for ((key, old_position), new_position) in
+                self.entries.into_iter().zip(compacted_positions)
+            {
+                let entry = match writer.store.get(&key) {
+                    Some(entry) if entry.value().load() == old_position => {
+                        entry
+                    }
+                    _ => continue,
+                };
+                match new_position {
+                    Some(new_position) => entry.value().store(new_position),
+                    // The key had expired, so it was not copied.
+                    None => {
+                        entry.remove();
+                    }
+                }
+            }
+            // Readers that race with the deletion below retry against the
+            // index once they see the safe point has moved past their
+            // segment.
+            writer.safe_point.store(self.generation, Ordering::SeqCst);
+        }
+        drop(writer);
+
+        for generation in get_sorted_generations(&self.directory)? {
+            if generation >= self.generation {
+                break;
+            }
+            hint::remove_hint(&self.directory, generation)?;
+            fs::remove_file(get_path_for_index(&self.directory, generation))?;
+        }
+        Ok(())
+    }
+
+    /// Writes a copy of every entry in the snapshot to the compacted
+    /// segment, returning the position of each copy, or `None` for entries
+    /// that had expired and were dropped instead.
+    fn copy_entries(&self) -> Result<Vec<Option<Position>>> {
+        let compaction_path =
+            get_compaction_path(&self.directory, self.generation);
+        let mut compaction_writer =
+            BufWriterWithPosition::new(File::create(&compaction_path)?);
+        format::write_header(&mut compaction_writer)?;
+        let mut compacted_positions = Vec::with_capacity(self.entries.len());
+        let now = expiry::now();
+        for (_, position) in &self.entries {
+            if position.is_expired(now) {
+                compacted_positions.push(None);
+                continue;
             }
+            let start_position = compaction_writer.position;
+            let entry = self.reader.read_index(*position)?;
+            format::write_entry(&mut compaction_writer, &entry)?;
+            let compacted_position = Position::from((
+                self.generation,
+                start_position,
+                compaction_writer.position,
+            ));
+            compacted_positions.push(Some(
+                compacted_position.with_expiry(position.expires_at),
+            ));
+        }
+        compaction_writer.sync()?;
+        fs::rename(
+            compaction_path,
+            get_path_for_index(&self.directory, self.generation),
+        )?;
+
+        // The hint only speeds up the next open, so failing to write it
+        // does not fail the compaction.
+        let hint_positions: Vec<_> = self
+            .entries
+            .iter()
+            .zip(&compacted_positions)
+            .filter_map(|((key, _), position)| {
+                Some((key.clone(), (*position)?))
+            })
+            .collect();
+        if let Err(error) = hint::write_hint(
+            &self.directory,
+            self.generation,
+            compaction_writer.position,
+            &hint_positions,
+        ) {
+            error!("Failed to write hint file: {}", error);
+        }
+        Ok(compacted_positions)
+    }
+}
+
+fn get_path_for_index(directory: &Path, index: u64) -> PathBuf {
+    directory.join(format!("{}.log", index))
+}
+
+/// Creates the segment file for `generation`, ready for its first entry.
+fn create_segment(
+    directory: &Path,
+    generation: u64,
+) -> Result<BufWriterWithPosition<File>> {
+    let mut writer = BufWriterWithPosition::<File>::create(
+        directory.to_path_buf(),
+        generation,
+    )?;
+    format::write_header(&mut writer)?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Reopens the newest segment, `generation`, so that writing carries on in
+/// it rather than leaving it behind half empty.
+///
+/// A segment that is already full or in an older format is synced instead,
+/// since writing moves past it, and `None` is returned.
+fn reopen_segment(
+    directory: &Path,
+    generation: u64,
+    segment_size: u64,
+) -> Result<Option<BufWriterWithPosition<File>>> {
+    let path = get_path_for_index(directory, generation);
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let length = file.metadata()?.len();
+    if length == 0 {
+        // Recovery truncated a segment whose header was never completed.
+        let mut writer = BufWriterWithPosition::new(file);
+        format::write_header(&mut writer)?;
+        writer.flush()?;
+        return Ok(Some(writer));
+    }
+    if length < segment_size
+        && format::read_header(&mut file)? == Some(SegmentFormat::BinaryFrames)
+    {
+        return Ok(Some(BufWriterWithPosition::append(file)?));
+    }
+    file.sync_all()?;
+    Ok(None)
+}
+
+fn get_compaction_path(directory: &Path, index: u64) -> PathBuf {
+    directory.join(format!("{}.compaction", index))
+}
+
+/// Deletes compacted segments that were never renamed into place because
+/// the process stopped before compaction finished. The segments they were
+/// copied from are still intact.
+fn remove_unfinished_compactions(directory: &Path) -> Result<()> {
+    for dir_entry in directory.read_dir()? {
+        let path = dir_entry?.path();
+        if path.extension() == Some("compaction".as_ref()) {
+            fs::remove_file(path)?;
         }
-        start_position = end_position as u64;
     }
     Ok(())
 }
 
-fn get_descending_files_in_directory(directory: PathBuf) -> Vec<PathBuf> {
-    let mut files: Vec<PathBuf> = directory
-        .read_dir()
-        .expect("Could not read files in directory.")
-        .map(|dir_entry| dir_entry.unwrap().path())
-        .collect();
-    files.sort_unstable_by_key(|path| path.parse_number_from_path().unwrap());
-    files
+/// Applies an entry stored at `position` to the index and returns the
+/// number of bytes in the log it made stale.
+fn apply_entry(entry: Entry, position: Position, store: &Index) -> u64 {
+    match entry {
+        Entry::Set(key, ..) => index_position(key, position, store),
+        Entry::SetWithExpiry(key, _, expires_at) => {
+            let position = position.with_expiry(Some(expires_at));
+            index_position(key, position, store)
+        }
+        // The `Rm` entry itself is stale as soon as it is written, since
+        // compaction drops it along with the value it removed.
+        Entry::Rm(key) => {
+            let stale = store
+                .remove(&key)
+                .map_or(0, |old| old.value().load().length);
+            stale + position.length
+        }
+    }
+}
+
+/// Returns the position of `key` in the index, unless it has expired.
+fn live_position(store: &Index, key: &[u8]) -> Option<Position> {
+    let position = store.get(key)?.value().load();
+    if position.is_expired(expiry::now()) {
+        None
+    } else {
+        Some(position)
+    }
+}
+
+/// Returns the value an entry sets, or `None` for a removal.
+fn entry_value(entry: Entry) -> Option<Vec<u8>> {
+    match entry {
+        Entry::Set(.., value) | Entry::SetWithExpiry(_, value, _) => {
+            Some(value)
+        }
+        Entry::Rm(..) => None,
+    }
+}
+
+/// Points `key` at the entry stored at `position` and returns the number
+/// of bytes in the log its previous entry made stale.
+///
+/// Only the writer, or a store being opened, updates the index, so nothing
+/// can insert the key between the lookup and the insert.
+fn index_position(key: Vec<u8>, position: Position, store: &Index) -> u64 {
+    match store.get(&key) {
+        Some(old) => old.value().swap(position).length,
+        None => {
+            store.insert(key, AtomicCell::new(position));
+            0
+        }
+    }
+}
+
+/// Replays a segment into the index, returning the number of stale bytes
+/// it contributed.
+///
+/// A torn or corrupted entry ends the replay. In the newest segment the
+/// segment is truncated to the entries before it if `repair` is set; in
+/// any other segment it is reported as an error.
+fn load_entry(
+    directory: &Path,
+    generation: u64,
+    store: &Index,
+    is_newest: bool,
+    repair: bool,
+) -> Result<u64> {
+    let path = get_path_for_index(directory, generation);
+    let mut reader = BufReaderWithPosition::new(File::open(&path)?)?;
+    let mut uncompacted = 0;
+
+    let invalid_position = match format::read_header(&mut reader)? {
+        None => Some(0),
+        Some(SegmentFormat::Legacy) => {
+            let stream_position = reader.position;
+            let mut start_position = stream_position;
+            let mut stream =
+                Deserializer::from_reader(reader).into_iter::<JsonEntry>();
+            loop {
+                match stream.next() {
+                    Some(Ok(entry)) => {
+                        let end_position =
+                            stream_position + stream.byte_offset() as u64;
+                        uncompacted += apply_entry(
+                            entry.into(),
+                            (generation, start_position, end_position).into(),
+                            store,
+                        );
+                        start_position = end_position;
+                    }
+                    Some(Err(..)) => break Some(start_position),
+                    None => break None,
+                }
+            }
+        }
+        Some(segment_format) => {
+            // The start of the batch being replayed, and its entries so far.
+            let mut batch: Option<(u64, Vec<(Entry, Position)>)> = None;
+            loop {
+                let start_position = reader.position;
+                let frame = format::read_frame(segment_format, &mut reader)?;
+                let position = Position::from((
+                    generation,
+                    start_position,
+                    reader.position,
+                ));
+                match (frame, &mut batch) {
+                    (Frame::Entry(entry), Some((.., entries))) => {
+                        entries.push((entry, position));
+                    }
+                    (Frame::Entry(entry), None) => {
+                        uncompacted += apply_entry(entry, position, store);
+                    }
+                    (Frame::BeginBatch, None) => {
+                        uncompacted += position.length;
+                        batch = Some((start_position, Vec::new()));
+                    }
+                    (Frame::CommitBatch, Some((.., entries))) => {
+                        uncompacted += position.length;
+                        for (entry, position) in entries.drain(..) {
+                            uncompacted += apply_entry(entry, position, store);
+                        }
+                        batch = None;
+                    }
+                    (Frame::End, None) => break None,
+                    // A batch that was never committed is dropped along
+                    // with everything after it.
+                    (_, Some((batch_start, ..))) => break Some(*batch_start),
+                    (_, None) => break Some(start_position),
+                }
+            }
+        }
+    };
+
+    if let Some(invalid_position) = invalid_position {
+        if !is_newest {
+            return Err(KvsError::Corruption {
+                file: path,
+                offset: invalid_position,
+                reason: "Torn or invalid entry in an older segment.".into(),
+            });
+        }
+        if !repair {
+            warn!(
+                "Skipping torn entry in segment {} at offset {}",
+                generation, invalid_position
+            );
+            return Ok(uncompacted);
+        }
+        warn!(
+            "Truncating torn entry in segment {} at offset {}",
+            generation, invalid_position
+        );
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(invalid_position)?;
+        file.sync_all()?;
+    }
+    Ok(uncompacted)
+}
+
+/// Lists the generations of the log segments in `directory`, oldest first.
+fn get_sorted_generations(directory: &Path) -> Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for dir_entry in directory.read_dir()? {
+        let path = dir_entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            generations.push(path.parse_number_from_path()?);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
 }
@@ -0,0 +1,45 @@
+use super::{KvsError, Result};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+const LOCK_FILE: &str = "LOCK";
+
+/// An advisory lock on a store's data directory, held by its writer.
+///
+/// The lock is taken on a `LOCK` file inside the directory, which also
+/// records the PID of the owning process, and is released when this is
+/// dropped or the process exits. The file itself is left in place.
+#[derive(Debug)]
+pub struct DirectoryLock {
+    _file: File,
+}
+
+impl DirectoryLock {
+    /// Takes the lock on `directory`, failing if another handle to the
+    /// store already holds it, whether in this process or another.
+    pub fn acquire(directory: &Path) -> Result<DirectoryLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(directory.join(LOCK_FILE))?;
+        if file.try_lock_exclusive().is_err() {
+            let mut owner = String::new();
+            file.read_to_string(&mut owner)?;
+            return Err(KvsError::Locked {
+                path: directory.to_path_buf(),
+                owner: owner.trim().to_owned(),
+            });
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", process::id())?;
+        file.flush()?;
+        Ok(DirectoryLock { _file: file })
+    }
+}
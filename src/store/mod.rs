@@ -1,14 +1,21 @@
+mod durability;
 mod entry;
 mod error;
+mod format;
+mod hint;
 mod kvstore;
+mod lock;
+mod options;
 mod path_buf;
 mod position;
 mod reader;
 mod writer;
 
+pub use durability::Durability;
 pub use entry::Entry;
 pub use error::{KvsError, Result};
 pub use kvstore::KvStore;
+pub use options::KvStoreOptions;
 pub use path_buf::ParsePath;
 pub use position::Position;
 pub use reader::BufReaderWithPosition;
@@ -0,0 +1,126 @@
+use super::{Durability, KvStore, Result};
+use std::path::PathBuf;
+
+const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024;
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_DIRECTORY_NAME: &str = ".kvs";
+
+/// Tunable settings for opening a `KvStore`.
+///
+/// ```rust
+/// use kvs::{KvStoreOptions, KvsEngine};
+/// use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStoreOptions::new()
+///     .segment_size(64 * 1024)
+///     .open(temp_dir.path())
+///     .unwrap();
+/// store.set(String::from("city"), String::from("Lisbon")).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) segment_size: u64,
+    pub(super) compaction_threshold: u64,
+    pub(super) durability: Durability,
+    pub(super) read_only: bool,
+    pub(super) create_if_missing: bool,
+    pub(super) error_if_exists: bool,
+    pub(super) directory_name: String,
+}
+
+impl KvStoreOptions {
+    /// Creates the default set of options, which is what `KvStore::open`
+    /// uses.
+    pub fn new() -> Self {
+        KvStoreOptions {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            durability: Durability::default(),
+            read_only: false,
+            create_if_missing: true,
+            error_if_exists: false,
+            directory_name: String::from(DEFAULT_DIRECTORY_NAME),
+        }
+    }
+
+    /// Sets the size in bytes after which the active log segment is
+    /// closed and a new one is started. Defaults to 1 MiB.
+    ///
+    /// A segment may grow slightly past this size, since the entry that
+    /// crosses the threshold is always written in full.
+    pub fn segment_size(&mut self, segment_size: u64) -> &mut Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Sets how many bytes of stale entries may accumulate in the log
+    /// before it is compacted. Defaults to 1 MiB.
+    ///
+    /// An entry becomes stale once its key is overwritten or removed.
+    pub fn compaction_threshold(
+        &mut self,
+        compaction_threshold: u64,
+    ) -> &mut Self {
+        self.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Sets when writes are synced to disk. Defaults to
+    /// `Durability::Never`.
+    ///
+    /// See `Durability` for what each mode guarantees.
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets whether the store is opened for reading only. Defaults to
+    /// `false`.
+    ///
+    /// A read-only store never creates, modifies, or removes files, and
+    /// rejects every write with an error. It sees the log as it was when
+    /// it was opened. It does not take the directory lock, so it can be
+    /// opened while another handle is writing to the store.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets whether the data directory is created when it does not exist.
+    /// Defaults to `true`.
+    ///
+    /// If `false`, opening a store that does not exist yet is an error.
+    /// A read-only store is never created.
+    pub fn create_if_missing(&mut self, create_if_missing: bool) -> &mut Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Sets whether opening a store whose data directory already exists
+    /// is an error. Defaults to `false`.
+    pub fn error_if_exists(&mut self, error_if_exists: bool) -> &mut Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Sets the name of the data directory created under the path the
+    /// store is opened at. Defaults to `.kvs`.
+    pub fn directory_name(
+        &mut self,
+        directory_name: impl Into<String>,
+    ) -> &mut Self {
+        self.directory_name = directory_name.into();
+        self
+    }
+
+    /// Opens the store under `path` using these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.clone())
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions::new()
+    }
+}
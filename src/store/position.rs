@@ -1,8 +1,21 @@
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Position {
     pub file_index: u64,
     pub start_position: u64,
     pub length: u64,
+    /// When the entry expires, in milliseconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+impl Position {
+    pub fn with_expiry(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 impl From<(u64, u64, u64)> for Position {
@@ -13,6 +26,7 @@ impl From<(u64, u64, u64)> for Position {
             file_index,
             start_position,
             length: end_position - start_position,
+            expires_at: None,
         }
     }
 }
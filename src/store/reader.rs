@@ -5,7 +5,7 @@ use std::io::{BufReader, Read, Seek, SeekFrom};
 #[derive(Debug)]
 pub struct BufReaderWithPosition<R: Read> {
     reader: BufReader<R>,
-    position: u64,
+    pub position: u64,
 }
 
 impl<R: Read> BufReaderWithPosition<R> {
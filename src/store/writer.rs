@@ -1,29 +1,64 @@
 use crate::Result;
 use std::fs::File;
 use std::io;
-use std::io::{BufWriter, Seek, Write};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::mem;
 use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct BufWriterWithPosition<W: Write + Seek> {
-    directory: PathBuf,
     writer: BufWriter<W>,
     pub position: u64,
 }
 
 impl<W: Write + Seek> BufWriterWithPosition<W> {
+    pub fn new(writer: W) -> Self {
+        BufWriterWithPosition {
+            writer: BufWriter::new(writer),
+            position: 0,
+        }
+    }
+
+    /// Wraps `writer` to append to it, starting from its end.
+    pub fn append(mut writer: W) -> Result<Self> {
+        let position = writer.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPosition {
+            writer: BufWriter::new(writer),
+            position,
+        })
+    }
+
     pub fn create(
         directory: PathBuf,
-        next_command_position: u64,
+        generation: u64,
     ) -> Result<BufWriterWithPosition<File>> {
-        let mut new_log_path = directory.clone();
-        new_log_path.push(format!("{}.log", next_command_position));
+        let mut new_log_path = directory;
+        new_log_path.push(format!("{}.log", generation));
 
-        Ok(BufWriterWithPosition {
-            directory,
-            writer: BufWriter::new(File::create(new_log_path)?),
-            position: 0,
-        })
+        Ok(BufWriterWithPosition::new(File::create(new_log_path)?))
+    }
+}
+
+impl BufWriterWithPosition<File> {
+    /// Flushes buffered data and waits until it has reached the disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Discards anything still buffered and truncates the file back to
+    /// `position`, undoing a write that failed part-way.
+    pub fn rewind(&mut self, position: u64) -> Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        // Taking the old writer apart drops its buffer without flushing it.
+        let _ =
+            mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        let file = self.writer.get_mut();
+        file.set_len(position)?;
+        file.seek(SeekFrom::Start(position))?;
+        self.position = position;
+        Ok(())
     }
 }
 
@@ -0,0 +1,32 @@
+//! # Thread Pool
+//! This module contains the thread pools `kvs-server` can use to serve
+//! connections concurrently.
+
+use crate::Result;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// A pool of threads that runs jobs in the background.
+pub trait ThreadPool {
+    /// Creates a pool with the given number of threads.
+    ///
+    /// Returns an error if `threads` is 0 or any of the threads could not
+    /// be started.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Queues `job` to be run on one of the pool's threads.
+    ///
+    /// A panicking job must not reduce the number of threads available
+    /// to the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,22 @@
+use super::ThreadPool;
+use crate::Result;
+use std::thread;
+
+/// A "pool" that starts a brand new thread for every job.
+///
+/// This puts no bound on the number of threads and is only meant as a
+/// baseline to compare the other pools against.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
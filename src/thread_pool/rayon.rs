@@ -0,0 +1,33 @@
+use super::ThreadPool;
+use crate::options::no_threads;
+use crate::Result;
+use log::error;
+
+/// An adapter over `rayon`'s work-stealing thread pool.
+///
+/// Jobs that panic are reported to the pool's panic handler instead of
+/// aborting the process, which is what `rayon` does without one.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        // `rayon` would pick a number of threads itself for 0.
+        if threads == 0 {
+            return Err(no_threads());
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| error!("A job panicked in the thread pool."))
+            .build()?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job)
+    }
+}
@@ -0,0 +1,57 @@
+use super::ThreadPool;
+use crate::options::no_threads;
+use crate::Result;
+use log::error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of worker threads pulling jobs from a single queue.
+///
+/// Jobs that panic are caught on the worker, so the pool keeps its full
+/// number of threads for as long as it is alive. Workers exit once the
+/// pool is dropped and the queue has drained.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        if threads == 0 {
+            return Err(no_threads());
+        }
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new().spawn(move || run_worker(&receiver))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("The thread pool has no workers left.");
+    }
+}
+
+fn run_worker(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is only held while waiting for the next job, so other
+        // workers can pick up jobs while this one is running.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("A job panicked in the thread pool.");
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Counts the allocations made by each thread, so that background threads
+// and other tests do not affect the count.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations(run: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    run();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+fn get_into_repeatedly(store: &KvStore, value: &mut Vec<u8>) -> usize {
+    count_allocations(|| {
+        for _ in 0..100 {
+            assert!(store.get_into(b"key1", value).unwrap());
+            assert!(store.get_into(b"key2", value).unwrap());
+            assert!(!store.get_into(b"key3", value).unwrap());
+        }
+    })
+}
+
+// Once the buffer is large enough and the segments are open, `get_into`
+// should not allocate, before or after a compaction.
+#[test]
+fn get_into_does_not_allocate() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key1", "value3")?;
+
+    let mut value = Vec::with_capacity(64);
+    assert!(store.get_into(b"key1", &mut value)?);
+    assert_eq!(get_into_repeatedly(&store, &mut value), 0);
+    assert_eq!(value, b"");
+
+    store.compact()?;
+    while store.is_compacting() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(store.get_into(b"key1", &mut value)?);
+    assert_eq!(value, b"value3");
+    assert_eq!(get_into_repeatedly(&store, &mut value), 0);
+    Ok(())
+}
@@ -1,3 +1,7 @@
+// The original tests pass arguments as `&[..]`, which newer versions of
+// clippy flag as a needless borrow.
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
@@ -129,6 +133,39 @@ fn client_cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client` should not accept options that only the server uses
+#[test]
+fn client_cli_server_options() {
+    let temp_dir = TempDir::new().unwrap();
+    for option in [
+        ["--thread-pool", "naive"],
+        ["--threads", "2"],
+        ["--durability", "always"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key"])
+            .args(option)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("wasn't expected"));
+    }
+}
+
+// `kvs-server` should refuse to start a thread pool with no threads
+#[test]
+fn server_cli_no_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0", "--addr", "127.0.0.1:4018"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("at least one thread"));
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
@@ -151,6 +188,18 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server` should reject engines it does not know about
+#[test]
+fn server_cli_invalid_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "unknown", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -164,10 +213,10 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be reaped");
 
     let content = fs::read_to_string(&stderr_path)
         .expect("unable to read from stderr file");
-    assert_eq!(content, String::from(""));
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
     assert!(content.contains("kvs"));
     assert!(content.contains("127.0.0.1:4001"));
@@ -186,6 +235,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -205,6 +255,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -226,6 +277,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -307,6 +359,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -337,3 +390,326 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Starts a server in `temp_dir`, returning a sender that stops it and the
+// handle of the thread waiting to do so.
+fn start_server(
+    engine: &str,
+    addr: &str,
+    temp_dir: &TempDir,
+) -> (mpsc::SyncSender<()>, thread::JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be reaped");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+fn cli_scan(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(engine, addr, &temp_dir);
+
+    for (key, value) in &[("a", "1"), ("b:1", "2"), ("b:2", "3"), ("c", "4")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a\t1\nb:1\t2\nb:2\t3\nc\t4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--start", "b", "--end", "c", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b:1\t2\nb:2\t3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "b:", "--reverse", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b:2\t3\nb:1\t2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--limit", "1", "--reverse", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c\t4\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_scan_kvs_engine() {
+    cli_scan("kvs", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_scan_sled_engine() {
+    cli_scan("sled", "127.0.0.1:4008");
+}
+
+fn cli_keys_and_count(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(engine, addr, &temp_dir);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["count", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+
+    for key in &["user:1", "user:2", "team:1"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("team:1\nuser:1\nuser:2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "--prefix", "user:", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\nuser:2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["count", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("3\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_keys_and_count_kvs_engine() {
+    cli_keys_and_count("kvs", "127.0.0.1:4009");
+}
+
+#[test]
+fn cli_keys_and_count_sled_engine() {
+    cli_keys_and_count("sled", "127.0.0.1:4010");
+}
+
+fn cli_batch(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(engine, addr, &temp_dir);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key2 value with spaces\n\nrm key1\nset key3 value3\n")
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue with spaces\nkey3\tvalue3\n");
+
+    // A malformed line rejects the whole batch.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("rm key2\nincr key3\n")
+        .assert()
+        .failure()
+        .stderr(contains("line 2"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["count", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_batch_kvs_engine() {
+    cli_batch("kvs", "127.0.0.1:4011");
+}
+
+#[test]
+fn cli_batch_sled_engine() {
+    cli_batch("sled", "127.0.0.1:4012");
+}
+
+fn cli_incr(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(engine, addr, &temp_dir);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "-5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "name", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "name", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_incr_kvs_engine() {
+    cli_incr("kvs", "127.0.0.1:4013");
+}
+
+#[test]
+fn cli_incr_sled_engine() {
+    cli_incr("sled", "127.0.0.1:4014");
+}
+
+fn cli_ttl(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, handle) = start_server(engine, addr, &temp_dir);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "session", "token", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "session", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "name", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "name", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["expire", "name", "30", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "name", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("30\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["expire", "missing", "30", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_ttl_kvs_engine() {
+    cli_ttl("kvs", "127.0.0.1:4015");
+}
+
+#[test]
+fn cli_ttl_sled_engine() {
+    cli_ttl("sled", "127.0.0.1:4016");
+}
@@ -0,0 +1,304 @@
+// Many of these tests pass owned `String`s where a `&str` would do, to
+// check that callers written against the owned API keep compiling.
+#![allow(
+    clippy::unnecessary_to_owned,
+    clippy::needless_borrows_for_generic_args
+)]
+
+use kvs::{
+    ErrorCode, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Request,
+    Response, Result, ScanOptions, SharedQueueThreadPool, SledKvsEngine,
+    ThreadPool,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Serves `engine` on `addr` from a background thread for the rest of the
+// test run.
+fn start_server<E: KvsEngine>(engine: E, addr: &str) -> SocketAddr {
+    let addr = addr.parse().unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    thread::spawn(move || KvsServer::new(engine, pool).run(addr));
+    thread::sleep(Duration::from_millis(200));
+    addr
+}
+
+fn compare_and_swap(addr: SocketAddr) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    let key = || "leader".to_owned();
+
+    assert!(client.set_if_absent(key(), "node1".to_owned())?);
+    assert!(!client.set_if_absent(key(), "node2".to_owned())?);
+    assert!(!client.compare_and_swap(
+        key(),
+        Some("node2".to_owned()),
+        Some("node3".to_owned())
+    )?);
+    assert!(client.compare_and_swap(
+        key(),
+        Some("node1".to_owned()),
+        Some("node2".to_owned())
+    )?);
+    assert_eq!(client.get(key())?, Some("node2".to_owned()));
+    assert!(!client.remove_if_equals(key(), "node1".to_owned())?);
+    assert!(client.remove_if_equals(key(), "node2".to_owned())?);
+    assert_eq!(client.get(key())?, None);
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4101");
+    compare_and_swap(addr)
+}
+
+#[test]
+fn compare_and_swap_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    compare_and_swap(start_server(engine, "127.0.0.1:4102"))
+}
+
+fn expiration(addr: SocketAddr) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    let key = || "session".to_owned();
+
+    client.set_with_ttl(key(), "token".to_owned(), Duration::from_secs(60))?;
+    let ttl = client.ttl(key())?.expect("key should expire");
+    assert!(ttl > Duration::from_secs(50) && ttl <= Duration::from_secs(60));
+    assert_eq!(client.incr("hits".to_owned(), 1)?, 1);
+    client.expire("hits".to_owned(), Duration::from_secs(60))?;
+    assert_eq!(client.incr("hits".to_owned(), 1)?, 2);
+    assert!(client.ttl("hits".to_owned())?.is_some());
+
+    client.expire(key(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.get(key())?, None);
+    assert!(!client.contains_key(&key())?);
+    assert_eq!(client.keys("")?, vec!["hits".to_owned()]);
+    assert_eq!(client.len()?, 1);
+    assert!(client.ttl(key()).is_err());
+    assert!(client.expire(key(), Duration::from_secs(1)).is_err());
+    assert!(client.remove(key()).is_err());
+
+    client.set(key(), "token".to_owned())?;
+    assert_eq!(client.ttl(key())?, None);
+    Ok(())
+}
+
+#[test]
+fn expiration_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4103");
+    expiration(addr)
+}
+
+#[test]
+fn expiration_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    expiration(start_server(engine, "127.0.0.1:4104"))
+}
+
+// Keys and values may be borrowed as `&str`, `&String`, or byte slices.
+fn borrowed_keys(addr: SocketAddr) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    let key = String::from("key1");
+    client.set(&key, "value1")?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(client.get(&key)?, Some("value1".to_owned()));
+    assert_eq!(client.get(key.as_bytes())?, Some("value1".to_owned()));
+    assert!(client.compare_and_swap(&key, Some("value1"), Some("value2"))?);
+    assert_eq!(client.get(b"key1")?, Some("value2".to_owned()));
+    client.remove(key.as_str())?;
+    assert!(!client.contains_key(&key)?);
+    Ok(())
+}
+
+#[test]
+fn borrowed_keys_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4109");
+    borrowed_keys(addr)
+}
+
+#[test]
+fn borrowed_keys_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    borrowed_keys(start_server(engine, "127.0.0.1:4110"))
+}
+
+fn binary_keys_and_values(addr: SocketAddr) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    let key: &[u8] = &[0x00, 0xff, 0xfe];
+    // A value starting with 0xff must not be mistaken for one that carries
+    // an expiry time.
+    let value: &[u8] = &[0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+    client.set_bytes(key, value)?;
+    assert_eq!(client.get_bytes(key)?.as_deref(), Some(value));
+    assert_eq!(client.ttl_bytes(key)?, None);
+    assert!(client.compare_and_swap_bytes(key, Some(value), Some(&[0x80]))?);
+    assert_eq!(client.keys_bytes(&[0x00])?, vec![key.to_vec()]);
+    assert_eq!(
+        client.scan_bytes(.., ScanOptions::new())?,
+        vec![(key.to_vec(), vec![0x80])]
+    );
+
+    client.set_bytes_with_ttl(key, value, Duration::from_secs(60))?;
+    assert_eq!(client.get_bytes(key)?.as_deref(), Some(value));
+    assert!(client.ttl_bytes(key)?.is_some());
+    client.remove_bytes(key)?;
+    assert!(!client.contains_key_bytes(key)?);
+
+    // Values that are not UTF-8 cannot be read through the string API.
+    client.set_bytes(b"text", value)?;
+    assert!(client.get("text".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4105");
+    binary_keys_and_values(addr)
+}
+
+#[test]
+fn binary_keys_and_values_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    binary_keys_and_values(start_server(engine, "127.0.0.1:4106"))
+}
+
+fn error_codes(addr: SocketAddr) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    client.set("text", "abc")?;
+
+    assert!(matches!(
+        client.remove("missing"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(client.ttl("missing"), Err(KvsError::KeyNotFound)));
+    match client.incr("text", 1) {
+        Err(KvsError::Server {
+            code: ErrorCode::NotAnInteger,
+            message,
+        }) => assert!(message.contains("not an integer")),
+        result => panic!("expected a server error, got {:?}", result),
+    }
+    Ok(())
+}
+
+#[test]
+fn error_codes_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4107");
+    error_codes(addr)
+}
+
+#[test]
+fn error_codes_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    error_codes(start_server(engine, "127.0.0.1:4108"))
+}
+
+// The start of an encoded `Response::Error` with `code`, up to its message.
+fn error_response_bytes(code: u16) -> Vec<u8> {
+    let mut bytes = 10u32.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&code.to_le_bytes());
+    bytes
+}
+
+// Error codes are part of the protocol, so they must keep their numbers.
+#[test]
+fn error_codes_on_the_wire() -> Result<()> {
+    let response = Response::Error {
+        code: ErrorCode::KeyNotFound,
+        message: "Key not found".to_owned(),
+    };
+    let mut expected = error_response_bytes(1);
+    expected.extend_from_slice(&13u64.to_le_bytes());
+    expected.extend_from_slice(b"Key not found");
+    assert_eq!(bincode::serialize(&response)?, expected);
+
+    // Codes added by newer servers are read as `Other`.
+    let mut bytes = error_response_bytes(999);
+    bytes.extend_from_slice(&3u64.to_le_bytes());
+    bytes.extend_from_slice(b"new");
+    let response: Response = bincode::deserialize(&bytes)?;
+    assert!(matches!(
+        response,
+        Response::Error {
+            code: ErrorCode::Other,
+            ..
+        }
+    ));
+    Ok(())
+}
+
+// Keys and values should be sent as their raw bytes, not as text.
+#[test]
+fn compact_messages_on_the_wire() -> Result<()> {
+    let request = Request::Set {
+        key: vec![0xff; 16],
+        value: vec![0xff; 1024],
+    };
+    let variant_length = 4;
+    let length_prefix = 8;
+    assert_eq!(
+        bincode::serialize(&request)?.len(),
+        variant_length + length_prefix + 16 + length_prefix + 1024
+    );
+    Ok(())
+}
+
+// Reads the server's response from `stream`, which should be a protocol
+// error followed by the server closing the connection.
+fn assert_protocol_error(mut stream: TcpStream) -> Result<()> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let mut payload = vec![0; u32::from_le_bytes(length) as usize];
+    stream.read_exact(&mut payload)?;
+    let response: Response = bincode::deserialize(&payload)?;
+    assert!(matches!(
+        response,
+        Response::Error {
+            code: ErrorCode::Protocol,
+            ..
+        }
+    ));
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+// A request that cannot be decoded should be answered with a protocol
+// error before the server closes the connection.
+#[test]
+fn malformed_request() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4111");
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&3u32.to_le_bytes())?;
+    stream.write_all(&[0xff, 0xff, 0xff])?;
+
+    assert_protocol_error(stream)
+}
+
+// A request frame longer than the server accepts should be answered with a
+// protocol error without the server reading it.
+#[test]
+fn oversized_request() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4112");
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&u32::MAX.to_le_bytes())?;
+
+    assert_protocol_error(stream)
+}
@@ -1,4 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
+// Many of these tests pass owned `String`s where a `&str` would do, to
+// check that callers written against the owned API keep compiling. Borrowed
+// keys are covered by `borrowed_keys`.
+#![allow(clippy::unnecessary_to_owned)]
+
+use kvs::{
+    Durability, Engine, ErrorCode, KvStore, KvStoreOptions, KvsEngine,
+    KvsError, Result, Scan, ScanOptions, SledKvsEngine, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -7,7 +18,7 @@ use walkdir::WalkDir;
 fn get_stored_value() -> Result<()> {
     let temp_dir =
         TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -17,7 +28,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -29,7 +40,7 @@ fn get_stored_value() -> Result<()> {
 fn overwrite_value() -> Result<()> {
     let temp_dir =
         TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -38,7 +49,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -51,14 +62,14 @@ fn overwrite_value() -> Result<()> {
 fn get_non_existent_value() -> Result<()> {
     let temp_dir =
         TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -68,7 +79,7 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir =
         TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -77,20 +88,154 @@ fn remove_non_existent_key() -> Result<()> {
 fn remove_key() -> Result<()> {
     let temp_dir =
         TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+fn count_log_files(temp_dir: &TempDir) -> usize {
+    WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .count()
+}
+
+// Many writes should share a single log segment
+#[test]
+fn writes_share_segment() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(count_log_files(&temp_dir), 1);
+
+    // Reopening the store should keep writing to the same segment.
+    for _ in 0..10 {
+        drop(store);
+        store = KvStore::open(temp_dir.path())?;
+    }
+    store.set("key100", "value100")?;
+    assert_eq!(count_log_files(&temp_dir), 1);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..=100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// The active segment should roll over once it reaches the segment size
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .segment_size(1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(count_log_files(&temp_dir) > 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Clones of a store should be able to write from several threads at once
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for key_id in 0..100 {
+                    store
+                        .set(
+                            format!("key{}_{}", thread_id, key_id),
+                            format!("value{}", key_id),
+                        )
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Clones of a store should be able to read from several threads at once
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for offset in 0..100 {
+                    let key_id = (thread_id + offset) % 100;
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir =
         TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -120,7 +265,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -130,3 +275,1048 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Compaction should keep the latest value of every key and must not bring
+// removed keys back.
+#[test]
+fn compaction_keeps_latest_values() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .segment_size(4 * 1024)
+        .compaction_threshold(8 * 1024)
+        .open(temp_dir.path())?;
+
+    store.set(String::from("gone"), String::from("value"))?;
+    store.remove(String::from("gone"))?;
+    for iter in 0..50 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.remove(format!("key{}", iter))?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = if key_id == 49 {
+                None
+            } else {
+                Some(String::from("49"))
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.get(String::from("gone"))?, None);
+        Ok(())
+    };
+    check(&store)?;
+    assert!(count_log_files(&temp_dir) < 10);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// A manual compaction runs in the background while writes carry on
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .segment_size(4 * 1024)
+        .open(temp_dir.path())?;
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let log_files_before = count_log_files(&temp_dir);
+
+    store.compact()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), String::from("latest"))?;
+    }
+    while store.is_compacting() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(count_log_files(&temp_dir) < log_files_before);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(String::from("latest"))
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(String::from("latest"))
+        );
+    }
+    Ok(())
+}
+
+// A torn entry at the end of the newest segment should be dropped on open
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join(".kvs").join("0.log");
+    let log_length = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(log_length - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A corrupted entry in an older segment should be reported, not panic
+#[test]
+fn report_corrupted_segment() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // The first segment is already full at this size, so writing moves on
+    // to a new one and leaves it behind as an older segment.
+    let store = KvStoreOptions::new()
+        .segment_size(1)
+        .open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join(".kvs").join("0.log");
+    let mut contents = fs::read(&log_path)?;
+    let middle = contents.len() / 2;
+    contents[middle] ^= 0xff;
+    fs::write(&log_path, contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { file, .. }) => assert_eq!(file, log_path),
+        result => panic!("expected a corruption error, got {:?}", result.err()),
+    }
+    Ok(())
+}
+
+// Segments written as a bare stream of JSON entries should still be read
+#[test]
+fn open_legacy_json_segment() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let log_directory = temp_dir.path().join(".kvs");
+    fs::create_dir(&log_directory)?;
+    fs::write(
+        log_directory.join("0.log"),
+        r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}{"Rm":"key1"}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn json_frame(payload: &str) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload.as_bytes()).to_le_bytes());
+    frame.extend_from_slice(payload.as_bytes());
+    frame
+}
+
+// Segments in a format version this release does not read should be
+// rejected rather than treated as corrupted.
+#[test]
+fn reject_unsupported_format() -> Result<()> {
+    for &version in &[2u32, 4] {
+        let temp_dir = TempDir::new()
+            .expect("unable to create temporary working directory");
+        let log_directory = temp_dir.path().join(".kvs");
+        fs::create_dir(&log_directory)?;
+        let mut segment = b"KVS\0".to_vec();
+        segment.extend_from_slice(&version.to_le_bytes());
+        fs::write(log_directory.join("0.log"), segment)?;
+
+        match KvStore::open(temp_dir.path()) {
+            Err(KvsError::UnsupportedFormat { version: found }) => {
+                assert_eq!(found, version)
+            }
+            result => {
+                panic!("expected an unsupported format, got {:?}", result)
+            }
+        }
+    }
+    Ok(())
+}
+
+// Segments written with version 1 of the format hold JSON frames, and should
+// be rewritten in the current format once compacted.
+#[test]
+fn open_json_framed_segment() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let log_directory = temp_dir.path().join(".kvs");
+    fs::create_dir(&log_directory)?;
+    let mut segment = b"KVS\0".to_vec();
+    segment.extend_from_slice(&1u32.to_le_bytes());
+    segment.extend(json_frame(r#"{"Set":["key1","value1"]}"#));
+    segment.extend(json_frame(r#"{"Set":["key2","value2"]}"#));
+    segment.extend(json_frame(r#"{"Rm":"key1"}"#));
+    fs::write(log_directory.join("0.log"), segment)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.compact()?;
+    while store.is_compacting() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!log_directory.join("0.log").exists());
+    for entry in fs::read_dir(&log_directory)? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            let header = fs::read(path)?;
+            assert_eq!(&header[..8], b"KVS\0\x03\0\0\0");
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Values written under every durability mode should be readable after the
+// store is closed and reopened.
+#[test]
+fn durability_modes() -> Result<()> {
+    let durabilities = [
+        Durability::Always,
+        Durability::Writes(3),
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::Never,
+    ];
+    for &durability in &durabilities {
+        let temp_dir = TempDir::new()
+            .expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .durability(durability)
+            .open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        thread::sleep(Duration::from_millis(30));
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// `sled` should honor every durability mode the same way.
+#[test]
+fn sled_durability_modes() -> Result<()> {
+    let durabilities = [
+        Durability::Always,
+        Durability::Writes(3),
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::Never,
+    ];
+    for &durability in &durabilities {
+        let temp_dir = TempDir::new()
+            .expect("unable to create temporary working directory");
+        let engine =
+            SledKvsEngine::open_with_durability(temp_dir.path(), durability)?;
+        for key_id in 0..10 {
+            engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        engine.remove("key0")?;
+        thread::sleep(Duration::from_millis(30));
+
+        drop(engine);
+        let engine = SledKvsEngine::open(temp_dir.path())?;
+        assert_eq!(engine.get("key0")?, None);
+        for key_id in 1..10 {
+            assert_eq!(
+                engine.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn open_options() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStoreOptions::new()
+        .create_if_missing(false)
+        .open(temp_dir.path())
+        .is_err());
+    assert!(!temp_dir.path().join(".kvs").exists());
+
+    let store = KvStoreOptions::new()
+        .directory_name("data")
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("data").is_dir());
+    assert!(!temp_dir.path().join(".kvs").exists());
+
+    assert!(KvStoreOptions::new()
+        .directory_name("data")
+        .error_if_exists(true)
+        .open(temp_dir.path())
+        .is_err());
+
+    let store = KvStoreOptions::new()
+        .directory_name("data")
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Only one writable handle may be open on a store at a time, but read-only
+// handles can be opened alongside it.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let error = KvStore::open(temp_dir.path())
+        .expect_err("a locked store should not open");
+    match error {
+        KvsError::Locked { owner, .. } => {
+            assert_eq!(owner, std::process::id().to_string())
+        }
+        error => panic!("expected a locked store, got {:?}", error),
+    }
+
+    let read_only = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(read_only.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+fn snapshot_directory(
+    temp_dir: &TempDir,
+) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut files: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            let contents = fs::read(entry.path()).expect("unable to read file");
+            (entry.into_path(), contents)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+// A read-only store must leave the directory exactly as it found it, even
+// when the log ends in a torn entry that a writer would truncate.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path()),
+        Err(KvsError::StoreMissing(..))
+    ));
+    assert!(!temp_dir.path().join(".kvs").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let log_path = fs::read_dir(temp_dir.path().join(".kvs"))?
+        .map(|entry| entry.expect("unable to read directory").path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .max()
+        .expect("no log segment was written");
+    let length = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(length - 2)?;
+
+    let before = snapshot_directory(&temp_dir);
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    drop(store);
+    assert_eq!(snapshot_directory(&temp_dir), before);
+    Ok(())
+}
+
+fn compact_and_wait(store: &KvStore) -> Result<()> {
+    store.compact()?;
+    while store.is_compacting() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+fn files_with_extension(
+    temp_dir: &TempDir,
+    extension: &str,
+) -> Vec<std::path::PathBuf> {
+    let mut paths: Vec<_> = fs::read_dir(temp_dir.path().join(".kvs"))
+        .expect("unable to read the log directory")
+        .map(|entry| entry.expect("unable to read directory").path())
+        .filter(|path| path.extension() == Some(extension.as_ref()))
+        .collect();
+    paths.sort();
+    paths
+}
+
+// Compacted segments should be loaded from their hint files on open, and
+// replayed in full when the hint cannot be trusted.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    compact_and_wait(&store)?;
+    drop(store);
+
+    let hints = files_with_extension(&temp_dir, "hint");
+    assert_eq!(hints.len(), 1);
+    let compacted_log = hints[0].with_extension("log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(store);
+
+    // Corrupting the last entry of the compacted segment goes unnoticed
+    // while the hint is used, since values are not read on open.
+    let mut segment = fs::read(&compacted_log)?;
+    let last = segment.len() - 1;
+    segment[last] ^= 0xff;
+    fs::write(&compacted_log, &segment)?;
+    KvStore::open(temp_dir.path())?;
+
+    // Without a valid hint, the segment is replayed and the corruption found.
+    let mut hint = fs::read(&hints[0])?;
+    hint[10] ^= 0xff;
+    fs::write(&hints[0], &hint)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    fs::remove_file(&hints[0])?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+fn collect_scan(scan: Scan) -> Result<Vec<(String, String)>> {
+    scan.collect()
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in &[("a", "1"), ("b:1", "2"), ("b:2", "3"), ("c", "4")] {
+        store.set(key.to_string(), value.to_string())?;
+    }
+    store.set("b:1".to_owned(), "5".to_owned())?;
+    store.remove("c".to_owned())?;
+
+    assert_eq!(
+        collect_scan(store.scan(.., ScanOptions::new())?)?,
+        pairs(&[("a", "1"), ("b:1", "5"), ("b:2", "3")])
+    );
+    assert_eq!(
+        collect_scan(
+            store.scan("a".to_owned().."b:2".to_owned(), ScanOptions::new())?
+        )?,
+        pairs(&[("a", "1"), ("b:1", "5")])
+    );
+    assert_eq!(
+        collect_scan(store.scan_prefix("b:", ScanOptions::new().reverse())?)?,
+        pairs(&[("b:2", "3"), ("b:1", "5")])
+    );
+    assert_eq!(
+        collect_scan(store.scan(.., ScanOptions::new().limit(2))?)?,
+        pairs(&[("a", "1"), ("b:1", "5")])
+    );
+    assert_eq!(
+        collect_scan(store.scan_prefix("d", ScanOptions::new())?)?,
+        []
+    );
+    Ok(())
+}
+
+#[test]
+fn keys_len_and_contains_key() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 0);
+    assert!(store.is_empty()?);
+
+    for key in &["user:1", "user:2", "user:3", "team:1"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.set("user:1".to_owned(), "other".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(store.len()?, 3);
+    assert_eq!(store.keys("")?, vec!["team:1", "user:1", "user:2"]);
+    assert_eq!(store.keys("user:")?, vec!["user:1", "user:2"]);
+    assert!(store.keys("users")?.is_empty());
+    assert!(store.contains_key("team:1")?);
+    assert!(!store.contains_key("user:3")?);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 3);
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .segment_size(64)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    for key_id in 2..10 {
+        batch.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    batch.remove("key1".to_owned()).remove("missing".to_owned());
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        for key_id in 2..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    Ok(())
+}
+
+// A batch whose commit marker never made it to disk should be dropped as a
+// whole on recovery, without losing the writes before it.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Cut off the commit marker, a frame header and a one-byte payload.
+    let log_path = files_with_extension(&temp_dir, "log")
+        .into_iter()
+        .filter(|path| fs::metadata(path).is_ok_and(|meta| meta.len() > 8))
+        .max()
+        .expect("no log segment was written");
+    let length = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(length - 9)?;
+    // Make the torn segment the newest one again.
+    for path in files_with_extension(&temp_dir, "log") {
+        if path > log_path {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "key1".to_owned();
+    let value = |value: &str| Some(value.to_owned());
+
+    assert!(!store.compare_and_swap(
+        key(),
+        value("value1"),
+        value("value2")
+    )?);
+    assert!(store.compare_and_swap(key(), None::<String>, None)?);
+    assert!(store.set_if_absent(key(), "value1".to_owned())?);
+    assert!(!store.set_if_absent(key(), "value2".to_owned())?);
+    assert!(!store.compare_and_swap(key(), None, value("value2"))?);
+    assert!(store.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert!(!store.remove_if_equals(key(), "value1".to_owned())?);
+    assert_eq!(store.get(key())?, value("value2"));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(key())?, value("value2"));
+    assert!(store.remove_if_equals(key(), "value2".to_owned())?);
+    assert_eq!(store.get(key())?, None);
+    Ok(())
+}
+
+// Counters built on compare-and-swap should not lose any increments when
+// updated from several threads at once.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                loop {
+                    let current = store.get("counter".to_owned()).unwrap();
+                    let next = current
+                        .as_ref()
+                        .map(|count| count.parse::<u32>().unwrap() + 1)
+                        .unwrap()
+                        .to_string();
+                    if store
+                        .compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some(next),
+                        )
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn incr_and_decr() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.decr("counter".to_owned(), 3)?, -5);
+    assert_eq!(store.get("counter".to_owned())?, Some("-5".to_owned()));
+
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert!(store.incr("name".to_owned(), 1).is_err());
+    assert_eq!(store.get("name".to_owned())?, Some("kvs".to_owned()));
+    store.set("big".to_owned(), i64::MAX.to_string())?;
+    assert!(store.incr("big".to_owned(), 1).is_err());
+    assert!(store.decr("counter".to_owned(), i64::MIN).is_err());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 0)?, -5);
+    Ok(())
+}
+
+#[test]
+fn concurrent_incr() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                store.incr("counter".to_owned(), 1).unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn expiration() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let short = Duration::from_millis(100);
+
+    store.set_with_ttl("a".to_owned(), "1".to_owned(), short)?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+    store.expire("c".to_owned(), short)?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.ttl("b".to_owned())?, None);
+    assert!(store.ttl("c".to_owned())?.is_some());
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, None);
+    assert!(!store.contains_key("a")?);
+    assert_eq!(store.keys("")?, vec!["b".to_owned()]);
+    assert_eq!(store.len()?, 1);
+    assert_eq!(
+        collect_scan(store.scan(.., ScanOptions::new())?)?,
+        pairs(&[("b", "2")])
+    );
+    assert!(store.remove("a".to_owned()).is_err());
+    assert!(store.ttl("a".to_owned()).is_err());
+    assert!(store.expire("a".to_owned(), short).is_err());
+
+    // Setting an expired key brings it back without an expiry.
+    store.set("a".to_owned(), "4".to_owned())?;
+    assert_eq!(store.ttl("a".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn expiration_survives_reopen_and_compaction() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let long = Duration::from_secs(600);
+
+    store.set_with_ttl("session".to_owned(), "token".to_owned(), long)?;
+    assert_eq!(store.incr("hits".to_owned(), 1)?, 1);
+    store.expire("hits".to_owned(), long)?;
+    assert_eq!(store.incr("hits".to_owned(), 1)?, 2);
+    for i in 0..100 {
+        let key = format!("temp{}", i);
+        store.set_with_ttl(key, "x".to_owned(), Duration::from_millis(50))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("session".to_owned())?.unwrap() > long / 2);
+    assert!(store.ttl("hits".to_owned())?.unwrap() > long / 2);
+    assert_eq!(store.len()?, 2);
+
+    // Compaction drops the expired entries and keeps the rest, along with
+    // their expiry, which the hint files must carry across a reopen.
+    compact_and_wait(&store)?;
+    assert_eq!(
+        store.keys("")?,
+        vec!["hits".to_owned(), "session".to_owned()]
+    );
+    drop(store);
+    assert!(!files_with_extension(&temp_dir, "hint").is_empty());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("2".to_owned()));
+    assert!(store.ttl("session".to_owned())?.unwrap() > long / 2);
+    assert_eq!(store.len()?, 2);
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = |suffix: u8| vec![0xff, 0x00, suffix];
+
+    store.set_bytes(&key(1), &[0xc3, 0x28])?;
+    store.set_bytes(&key(2), &[])?;
+    store.set_bytes(&[0xff, 0x01], b"after")?;
+    store.set("text".to_owned(), "plain".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set_bytes(key(3), vec![0x80]).remove_bytes(key(2));
+    store.write_batch(batch)?;
+    assert_eq!(store.incr_bytes(&key(4), 7)?, 7);
+
+    assert_eq!(store.get_bytes(&key(1))?, Some(vec![0xc3, 0x28]));
+    assert_eq!(store.get_bytes(&key(2))?, None);
+    assert!(store.get("text".to_owned()).is_ok());
+    assert!(store.keys("").is_err());
+    assert_eq!(
+        store.keys_bytes(&[0xff, 0x00])?,
+        vec![key(1), key(3), key(4)]
+    );
+    let pairs = store
+        .scan_prefix_bytes(&[0xff], ScanOptions::new().reverse())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (vec![0xff, 0x01], b"after".to_vec()),
+            (key(4), b"7".to_vec()),
+            (key(3), vec![0x80]),
+            (key(1), vec![0xc3, 0x28]),
+        ]
+    );
+
+    // Binary keys are carried through compaction, hint files, and replay.
+    compact_and_wait(&store)?;
+    store.remove_bytes(&key(3))?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key(1))?, Some(vec![0xc3, 0x28]));
+    assert!(!store.contains_key_bytes(&key(3))?);
+    assert_eq!(store.get("text".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(store.len()?, 4);
+    Ok(())
+}
+
+// `get_into` should reuse the caller's buffer and accept borrowed keys.
+// Keys and values may be borrowed as `&str`, `&String`, or byte slices,
+// including empty ones.
+fn borrowed_keys<E: KvsEngine>(engine: &E) -> Result<()> {
+    let key = String::from("key1");
+    engine.set(&key, "value1")?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get(&key)?, Some("value1".to_owned()));
+    assert_eq!(engine.get(key.as_bytes())?, Some("value1".to_owned()));
+    assert_eq!(engine.get(b"key1")?, Some("value1".to_owned()));
+
+    engine.set(b"", b"")?;
+    assert_eq!(engine.get("")?, Some(String::new()));
+    assert!(engine.compare_and_swap("", Some(""), Some("empty"))?);
+    assert_eq!(engine.get(b"")?, Some("empty".to_owned()));
+    assert_eq!(engine.incr(b"hits", 2)?, 2);
+
+    assert!(engine.contains_key(&key)?);
+    engine.remove(key.as_str())?;
+    assert!(!engine.contains_key(key.as_bytes())?);
+    engine.remove(b"")?;
+    assert_eq!(engine.get("")?, None);
+    Ok(())
+}
+
+#[test]
+fn borrowed_keys_kvs_engine() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    borrowed_keys(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn borrowed_keys_sled_engine() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    borrowed_keys(&SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn get_into_reuses_buffer() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("short", "a")?;
+    store.set("long", "a much longer value")?;
+    store.set(b"binary", [0xff, 0x00])?;
+    store.set("removed", "gone")?;
+    store.remove("removed")?;
+
+    let mut buffer = Vec::with_capacity(64);
+    let capacity = buffer.capacity();
+    assert!(store.get_into(b"long", &mut buffer)?);
+    assert_eq!(buffer, b"a much longer value");
+    assert!(store.get_into(b"short", &mut buffer)?);
+    assert_eq!(buffer, b"a");
+    assert!(store.get_into(b"binary", &mut buffer)?);
+    assert_eq!(buffer, [0xff, 0x00]);
+    assert!(!store.get_into(b"removed", &mut buffer)?);
+    assert!(buffer.is_empty());
+    assert!(!store.get_into(b"missing", &mut buffer)?);
+    assert_eq!(buffer.capacity(), capacity);
+
+    // Values are read the same way from compacted segments.
+    compact_and_wait(&store)?;
+    assert!(store.get_into(b"long", &mut buffer)?);
+    assert_eq!(buffer, b"a much longer value");
+    let key = String::from("short");
+    assert_eq!(store.get(&key)?, Some("a".to_owned()));
+    assert!(store.contains_key(&key)?);
+    Ok(())
+}
+
+// Overwriting a key should never make it appear missing to a concurrent
+// reader.
+#[test]
+fn concurrent_reads_during_overwrites() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..2000 {
+                assert!(store.get("key".to_owned()).unwrap().is_some());
+            }
+        }));
+    }
+    for i in 0..2000 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// Each kind of failure should be reported as its own variant, with its own
+// code.
+#[test]
+fn error_variants() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("text", "abc")?;
+    store.set("max", i64::MAX.to_string())?;
+
+    assert!(matches!(
+        store.remove("missing"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(store.ttl("missing"), Err(KvsError::KeyNotFound)));
+    assert!(matches!(
+        store.incr("text", 1),
+        Err(KvsError::NotAnInteger { .. })
+    ));
+    assert!(matches!(
+        store.incr("max", 1),
+        Err(KvsError::IntegerOverflow { .. })
+    ));
+    store.set_bytes(b"binary", &[0xff])?;
+    assert!(matches!(
+        store.get("binary"),
+        Err(KvsError::InvalidUtf8(..))
+    ));
+    let error = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(matches!(error, KvsError::Locked { .. }));
+    assert_eq!(error.code(), ErrorCode::Locked);
+    assert!(matches!(
+        Engine::Sled.claim(temp_dir.path()),
+        Err(KvsError::WrongEngine {
+            found: Engine::Kvs,
+            requested: Engine::Sled,
+            ..
+        })
+    ));
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    let error = store.set("key", "value").unwrap_err();
+    assert!(matches!(error, KvsError::ReadOnly));
+    assert_eq!(error.code(), ErrorCode::ReadOnly);
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use kvs::{
+    NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 20;
+    const ADD_COUNT: usize = 1000;
+
+    let (sender, receiver) = channel();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let sender = sender.clone();
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            sender.send(()).unwrap();
+        });
+    }
+
+    for _ in 0..TASK_NUM {
+        receiver.recv().unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?)
+}
+
+// Jobs that panic should not take their worker down with them
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("intentional panic in a pooled job"));
+    }
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("intentional panic in a pooled job"));
+    }
+    spawn_counter(pool)
+}
+
+// A pool with a fixed number of workers cannot have none
+#[test]
+fn thread_pool_without_threads() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+    assert!(RayonThreadPool::new(0).is_err());
+}
@@ -0,0 +1,97 @@
+// These tests limit the size of the files the whole process may write, so
+// they live in their own test binary.
+#![cfg(unix)]
+
+use kvs::{KvStore, KvsEngine, Result, WriteBatch};
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+use tempfile::TempDir;
+
+// Held by each test, since the limit applies to every thread.
+static FILE_SIZE_LIMIT: Mutex<()> = Mutex::new(());
+
+fn exclusive() -> MutexGuard<'static, ()> {
+    FILE_SIZE_LIMIT
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+}
+
+// Makes writes to files fail past `limit` bytes, with an error instead of
+// the signal that would otherwise kill the process, and returns the limit
+// it replaced.
+fn limit_file_size(limit: libc::rlim_t) -> libc::rlim_t {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        assert_eq!(libc::getrlimit(libc::RLIMIT_FSIZE, &mut rlimit), 0);
+        let previous = rlimit.rlim_cur;
+        rlimit.rlim_cur = limit;
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &rlimit), 0);
+        previous
+    }
+}
+
+fn segment_length(temp_dir: &TempDir) -> u64 {
+    fs::metadata(temp_dir.path().join(".kvs").join("0.log"))
+        .expect("unable to read the log segment")
+        .len()
+}
+
+// A write that fails part-way should never reach the log, even once later
+// writes succeed.
+#[test]
+fn failed_write_is_discarded() -> Result<()> {
+    let _exclusive = exclusive();
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let previous = limit_file_size(segment_length(&temp_dir) + 8);
+    let result = store.set("failed", "x".repeat(100));
+    limit_file_size(previous);
+    assert!(result.is_err());
+    assert_eq!(store.get("failed")?, None);
+
+    store.set("key2", "value2")?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("failed")?, None);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A batch that fails part-way should be dropped as a whole, without taking
+// the writes that follow it along when the log is replayed.
+#[test]
+fn failed_batch_is_discarded() -> Result<()> {
+    let _exclusive = exclusive();
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    // Larger than the write buffer, so it is flushed before it is complete.
+    let mut batch = WriteBatch::new();
+    for key_id in 0..20 {
+        batch.set(format!("batch{}", key_id), "x".repeat(1024));
+    }
+    let previous = limit_file_size(segment_length(&temp_dir) + 8);
+    let result = store.write_batch(batch);
+    limit_file_size(previous);
+    assert!(result.is_err());
+
+    store.set("key2", "value2")?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("batch{}", key_id))?, None);
+    }
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}